@@ -0,0 +1,86 @@
+//! Compares the lookup index of books with binary search. Run with `cargo bench --bench book_probe`.
+
+use fourengine::bitboard::BOARD_WIDTH;
+use fourengine::book::{Book, BookFormat, BookWriter};
+use fourengine::position::Position;
+use std::hint::black_box;
+use std::time::Instant;
+
+const ROUNDS: usize = 20;
+
+/// Positions of the book in a shuffled order like in a search, together with positions one ply
+/// deeper that the book does not have
+fn queries(book: &Book) -> Vec<Position> {
+    let mut positions: Vec<Position> = book
+        .iter()
+        .flat_map(|entry| {
+            let position = entry.get_position();
+            [
+                Some(position),
+                position.position_after_drop(entry.get_position_code() as u32 % BOARD_WIDTH),
+            ]
+        })
+        .flatten()
+        .collect();
+    // xorshift
+    let mut state: u64 = 0x2545F4914F6CDD1D;
+    for i in (1..positions.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        positions.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+    positions
+}
+
+fn run(name: &str, book: &Book, queries: &[Position]) {
+    let mut found = 0;
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for position in queries {
+            if black_box(book.get_entry(black_box(position))).is_some() {
+                found += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    let lookups = ROUNDS * queries.len();
+    println!(
+        "{:<32} {:>8.1} ns/lookup ({} lookups, {} found)",
+        name,
+        elapsed.as_nanos() as f64 / lookups as f64,
+        lookups,
+        found
+    );
+}
+
+fn main() {
+    let book = Book::open("books/7x6-ply8.txt".as_ref()).expect("Cannot open the ply 8 book");
+    let queries = queries(&book);
+
+    let mut book_writer = BookWriter::create_sorted(vec![], BookFormat::Versioned);
+    for entry in book.iter() {
+        book_writer.write_entry(&entry).unwrap();
+    }
+    let bytes = book_writer.finish().unwrap();
+    let raw_book = Book::from_bytes(&bytes).unwrap();
+
+    let indexed_book = Book::open("books/7x6-ply8.txt".as_ref()).unwrap();
+    indexed_book.build_lookup_index();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for position in &queries {
+            black_box(black_box(position).normalize().to_position_code());
+        }
+    }
+    println!(
+        "{:<32} {:>8.1} ns/lookup",
+        "normalizing only",
+        start.elapsed().as_nanos() as f64 / (ROUNDS * queries.len()) as f64
+    );
+
+    run("binary search", &book, &queries);
+    run("binary search (versioned book)", &raw_book, &queries);
+    run("lookup index", &indexed_book, &queries);
+}
@@ -64,6 +64,7 @@ self.onmessage = async function (e) {
             score: struct.getScore(),
             workCount: struct.getWorkCount(),
             nps: Math.round(struct.getWorkCount() / duration),
+            bestMove: engine.bestMove(variation),
         };
         postMessage(message);
     } catch (error) {
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 #![allow(clippy::precedence)]
 
+use crate::error::ParseError;
 use std::fmt;
 use std::fmt::Formatter;
 
@@ -74,20 +75,29 @@ impl Bitboard {
     /// );
     /// assert_eq!(board_with_macro, board);
     /// ```
-    pub fn from_string(str: &str) -> Option<Bitboard> {
+    pub fn from_string(str: &str) -> Result<Bitboard, ParseError> {
         let mut bitboard = Bitboard::empty();
 
         for (y, line) in str.split_whitespace().rev().enumerate() {
             for (x, ch) in line.chars().enumerate() {
-                match ch {
-                    '1' => bitboard = bitboard.set_disc(x as u32, y as u32),
-                    '0' => {}
-                    _ => return None,
+                let (x, y) = (x as u32, y as u32);
+                if ch != '1' && ch != '0' {
+                    return Err(ParseError::InvalidCharacter {
+                        x,
+                        y,
+                        character: ch,
+                    });
+                }
+                if x >= BOARD_WIDTH || y > BOARD_HEIGHT {
+                    return Err(ParseError::CellOutOfBounds { x, y });
+                }
+                if ch == '1' {
+                    bitboard = bitboard.set_disc(x, y);
                 }
             }
         }
 
-        Some(bitboard)
+        Ok(bitboard)
     }
 
     pub fn has_won(&self) -> bool {
@@ -1,13 +1,20 @@
 use crate::benchmark::{format_large_number, Benchmark};
-use crate::bitboard::{Bitboard, BoardInteger, BOARD_HEIGHT, BOARD_WIDTH};
+use crate::bitboard::{Bitboard, BoardInteger, BOARD_HEIGHT, BOARD_WIDTH, POSITION_BITS};
 use crate::engine::Engine;
+use crate::error::{BookLocation, Error};
+use crate::external_sort::{ExternalSorter, SortOptions, SortedEntries};
 use crate::score::{Score, SCORE_BITS};
 use core::mem;
 use std::cmp::Ordering;
-use std::collections::{BTreeSet, HashSet};
-use std::fs::{create_dir_all, File};
-use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, Cursor, LineWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
 use std::{cmp, io};
 use crate::position::Position;
 use std::convert::TryInto;
@@ -19,19 +26,37 @@ pub fn get_path_for_ply(ply: u32) -> PathBuf {
     PathBuf::from(BOOK_FOLDER).join(format!("{}x{}-ply{}.txt", BOARD_WIDTH, BOARD_HEIGHT, ply))
 }
 
-/// Packs a position code and its score in one value
+/// The path of a book in the versioned format, which is preferred over the text book if it exists
+pub fn get_versioned_path_for_ply(ply: u32) -> PathBuf {
+    get_path_for_ply(ply).with_extension("fbk")
+}
+
+/// Packs a position code, its score and optional annotations in one value
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct BookEntry(BoardInteger);
 
+// annotations must not overlap with the position code and must be able to hold every value
+const _: () = {
+    assert!(POSITION_BITS <= BookEntry::MOVE_SHIFT);
+    assert!(BOARD_WIDTH < 1 << BookEntry::MOVE_BITS);
+};
+
+// every ply must have a bit in the ply sets of books
+const _: () = assert!(BOARD_WIDTH * BOARD_HEIGHT < u64::BITS);
+
 impl BookEntry {
     // Score is saved in the most significant bits by shifting left and the position can be derived
     // with a simple mask. An alternative design would have been to shift position left to make room
     // for score, which would have had the benefit that entries are numerically sorted in the file.
-    // The current design was chosen in case we want to encode more information in the empty space
-    // between score and position.
+    // The empty space between score and position is used for annotations, which is currently only
+    // the best move. Zero means that an annotation is unknown so entries written before annotations
+    // existed are still valid.
     const BYTE_COUNT: usize = mem::size_of::<BoardInteger>();
     const SCORE_SHIFT: u32 = (Self::BYTE_COUNT * 8) as u32 - SCORE_BITS;
-    const POSITION_MASK: BoardInteger = (1 << Self::SCORE_SHIFT) - 1;
+    const MOVE_BITS: u32 = 4;
+    const MOVE_SHIFT: u32 = Self::SCORE_SHIFT - Self::MOVE_BITS;
+    const ANNOTATION_BITS: u32 = Self::MOVE_BITS;
+    const POSITION_MASK: BoardInteger = (1 << Self::MOVE_SHIFT) - 1;
 
     pub fn new(position: &Position, score: Score) -> Self {
         let code = position.normalize().to_position_code();
@@ -39,10 +64,31 @@ impl BookEntry {
         BookEntry(code | score_bits)
     }
 
+    /// Creates an entry with the best move, which is a column of the given position and not of the
+    /// normalized one
+    pub fn annotated(position: &Position, score: Score, best_move: Option<u32>) -> Self {
+        let entry = Self::new(position, score);
+        let best_move = best_move.map(|x| {
+            if position.to_position_code() == entry.get_position_code() {
+                x
+            } else {
+                BOARD_WIDTH - 1 - x
+            }
+        });
+        entry.with_annotations(best_move)
+    }
+
+    /// Replaces the annotations. The best move is a column of the normalized position.
+    fn with_annotations(self, best_move: Option<u32>) -> Self {
+        debug_assert!(best_move.is_none_or(|x| x < BOARD_WIDTH));
+        let move_bits = best_move.map_or(0, |x| x as u64 + 1) << Self::MOVE_SHIFT;
+        let annotation_mask = ((1 << Self::ANNOTATION_BITS) - 1) << Self::MOVE_SHIFT;
+        BookEntry((self.0 & !annotation_mask) | move_bits)
+    }
+
     pub fn get_position(&self) -> Position {
-        let code = self.get_position_code();
-        Position::from_position_code(code)
-            .expect(&format!("Invalid position code in book: {:0>16X}", code))
+        // all constructors make sure that the position code is valid
+        Position::decode_position_code(self.get_position_code())
     }
 
     pub fn get_position_code(&self) -> BoardInteger {
@@ -53,28 +99,94 @@ impl BookEntry {
         Score::from_u64_fast(self.0 >> Self::SCORE_SHIFT)
     }
 
-    fn to_hex_string(&self) -> String {
-        format!(
+    /// Replaces the score but keeps the position and annotations
+    fn with_score(self, score: Score) -> Self {
+        let score_mask = ((1 << SCORE_BITS) - 1) << Self::SCORE_SHIFT;
+        BookEntry((self.0 & !score_mask) | (score as u64) << Self::SCORE_SHIFT)
+    }
+
+    /// The best column in the normalized position, i.e. the one returned by get_position
+    pub fn get_best_move(&self) -> Option<u32> {
+        let bits = (self.0 >> Self::MOVE_SHIFT) & ((1 << Self::MOVE_BITS) - 1);
+        bits.checked_sub(1).map(|x| x as u32)
+    }
+
+    /// Fails if the position cannot occur in a real game
+    pub fn validate(&self) -> Result<(), Error> {
+        let position = self.get_position();
+        position.validate().map_err(|errors| Error::Validation {
+            code: position.to_position_code(),
+            errors,
+        })
+    }
+
+    fn has_annotations(&self) -> bool {
+        self.get_best_move().is_some()
+    }
+
+    /// The annotations as a single number. Used by the compressed format.
+    fn get_annotation_bits(&self) -> u64 {
+        (self.0 >> Self::MOVE_SHIFT) & ((1 << Self::ANNOTATION_BITS) - 1)
+    }
+
+    fn with_annotation_bits(self, bits: u64) -> Option<Self> {
+        if bits >> Self::ANNOTATION_BITS != 0 {
+            return None;
+        }
+        let entry = BookEntry(self.0 | bits << Self::MOVE_SHIFT);
+        if entry.get_best_move().is_none_or(|x| x < BOARD_WIDTH) {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+
+    /// Annotations are appended after a space only if there are any so that the lines of books
+    /// without annotations stay the same. The best move is shown one-based like in variations, e.g.
+    /// "0000040812A04081+ 4".
+    fn to_hex_string(self) -> String {
+        let mut str = format!(
             "{}{}",
             self.get_position().as_hex_string(),
             self.get_score().to_char()
-        )
+        );
+        if let Some(x) = self.get_best_move() {
+            str.push_str(&format!(" {}", x + 1));
+        }
+        str
     }
 
     fn from_hex_string(line: &str) -> Option<BookEntry> {
         const HEX_LENGTH: usize = mem::size_of::<BoardInteger>() * 2;
-        if line.len() != HEX_LENGTH + 1 {
+        let (entry_str, annotations) = match line.split_once(' ') {
+            Some((entry_str, annotations)) => (entry_str, Some(annotations)),
+            None => (line, None),
+        };
+        if entry_str.len() != HEX_LENGTH + 1 {
             return None;
         }
 
-        let position_str = &line[0..HEX_LENGTH];
+        let position_str = &entry_str[0..HEX_LENGTH];
         let position_code = BoardInteger::from_str_radix(position_str, 16).ok()?;
-        let position = Position::from_position_code(position_code)?;
-
-        let score = Score::from_string(&line[HEX_LENGTH..]);
-        Some(BookEntry::new(&position, score))
+        let position = Position::from_position_code(position_code).ok()?;
+
+        let score = Score::from_string(&entry_str[HEX_LENGTH..]);
+        let entry = BookEntry::new(&position, score);
+        match annotations {
+            None => Some(entry),
+            Some(annotations) => {
+                let best_move = annotations.trim().parse::<u32>().ok()?;
+                if best_move == 0 || best_move > BOARD_WIDTH {
+                    return None;
+                }
+                // the hex code is not necessarily normalized so the column may have to be mirrored
+                Some(BookEntry::annotated(&position, score, Some(best_move - 1)))
+            }
+        }
     }
 
+    /// Like from_csv_string with the first player's perspective but the commas are optional and
+    /// blank cells may also be spaces
     fn from_verbose_string(line: &str) -> Option<BookEntry> {
         const CELL_COUNT: usize = (BOARD_WIDTH * BOARD_HEIGHT) as usize;
         let line: String = line.chars().filter(|x| *x != ',').collect();
@@ -84,26 +196,178 @@ impl BookEntry {
         let position_str = &line[0..CELL_COUNT];
         let score_str = &line[CELL_COUNT..];
 
-        let mut current = Bitboard::empty();
-        let mut other = Bitboard::empty();
-        for (i, ch) in position_str.chars().enumerate() {
+        let score = Score::from_string(score_str);
+        if score == Score::Unknown {
+            None
+        } else {
+            Self::from_cells(position_str.chars(), score, Perspective::FirstPlayer)
+        }
+    }
+
+    /// Parses the format of the UCI Connect-4 dataset: one field per cell in column-major order
+    /// starting from the bottom left, each x, o or b for blank, followed by the score as a word
+    /// such as win or draw-or-loss (see Score::to_word). The perspective tells whether x is the
+    /// first player or the player to move, and the score is for the same player.
+    fn from_csv_string(line: &str, perspective: Perspective) -> Option<BookEntry> {
+        const CELL_COUNT: usize = (BOARD_WIDTH * BOARD_HEIGHT) as usize;
+        let fields: Vec<&str> = line.trim().split(',').map(str::trim).collect();
+        if fields.len() != CELL_COUNT + 1 || fields[..CELL_COUNT].iter().any(|f| f.len() != 1) {
+            return None;
+        }
+        let score_str = fields[CELL_COUNT];
+        let score = Score::from_string(score_str);
+        if score == Score::Unknown && score_str != Score::Unknown.to_word() {
+            return None;
+        }
+        let cells = fields[..CELL_COUNT].iter().flat_map(|field| field.chars());
+        Self::from_cells(cells, score, perspective)
+    }
+
+    fn from_cells(
+        cells: impl Iterator<Item = char>,
+        score: Score,
+        perspective: Perspective,
+    ) -> Option<BookEntry> {
+        let mut x_discs = Bitboard::empty();
+        let mut o_discs = Bitboard::empty();
+        for (i, ch) in cells.enumerate() {
             let y = i as u32 % BOARD_HEIGHT;
             let x = i as u32 / BOARD_HEIGHT;
             match ch {
-                'X' | 'x' => current = current.set_disc(x, y),
-                'O' | 'o' => other = other.set_disc(x, y),
+                'X' | 'x' => x_discs = x_discs.set_disc(x, y),
+                'O' | 'o' => o_discs = o_discs.set_disc(x, y),
                 ' ' | 'b' => {}
                 _ => return None,
             }
         }
 
-        let position = Position::new(current, other);
-        let score = Score::from_string(score_str);
-        if score == Score::Unknown {
-            None
+        let mut position = Position::new(x_discs, o_discs);
+        let mut score = score;
+        if perspective == Perspective::FirstPlayer && position.get_ply() % 2 != 0 {
+            position = Position::new(o_discs, x_discs);
+            score = score.flip();
+        }
+        if !Position::is_valid_position_code(position.to_position_code()) {
+            return None;
+        }
+        Some(BookEntry::new(&position, score))
+    }
+
+    /// The inverse of from_csv_string. Annotations are not saved.
+    fn to_csv_string(self, perspective: Perspective) -> String {
+        let position = self.get_position();
+        let (x_discs, o_discs, score) =
+            if perspective == Perspective::FirstPlayer && position.get_ply() % 2 != 0 {
+                (position.other, position.current, self.get_score().flip())
+            } else {
+                (position.current, position.other, self.get_score())
+            };
+
+        let mut str = String::new();
+        for x in 0..BOARD_WIDTH {
+            for y in 0..BOARD_HEIGHT {
+                str.push(if x_discs.has_disc(x, y) {
+                    'x'
+                } else if o_discs.has_disc(x, y) {
+                    'o'
+                } else {
+                    'b'
+                });
+                str.push(',');
+            }
+        }
+        str.push_str(score.to_word());
+        str
+    }
+
+    /// A record of several lines for reading books by eye: the position code, a variation that
+    /// leads to the position (or '?' if none is found), the ply, the player to move, the score
+    /// for the player to move, the annotations if there are any and a diagram where X is the first
+    /// player. The best move is a one-based column of the diagram.
+    fn to_readable_string(self) -> String {
+        let position = self.get_position();
+        let to_move = if position.get_ply() % 2 == 0 {
+            'X'
+        } else {
+            'O'
+        };
+        let mut str = format!(
+            "code: {}\nvariation: {}\nply: {}\nto move: {}\nscore: {}\n",
+            position.as_hex_string(),
+            position
+                .guess_variation()
+                .unwrap_or_else(|| "?".to_string()),
+            position.get_ply(),
+            to_move,
+            self.get_score().to_word()
+        );
+        if let Some(best_move) = self.get_best_move() {
+            str.push_str(&format!("best move: {}\n", best_move + 1));
+        }
+        str.push_str(&position.to_string());
+        str
+    }
+
+    /// The inverse of to_readable_string. The position is read from the diagram and the other
+    /// fields except the score and annotations are optional, but they must agree with the diagram
+    /// if they are given so that edits that make a record inconsistent are noticed.
+    fn from_readable_string(record: &str) -> Option<BookEntry> {
+        let mut diagram = String::new();
+        let mut code = None;
+        let mut variation = None;
+        let mut ply = None;
+        let mut to_move = None;
+        let mut score = None;
+        let mut best_move = None;
+        for line in record.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                diagram.push_str(line);
+                diagram.push('\n');
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "code" => code = Some(BoardInteger::from_str_radix(value, 16).ok()?),
+                "variation" => variation = Some(value),
+                "ply" => ply = Some(value.parse::<u32>().ok()?),
+                "to move" => to_move = Some(value),
+                "score" => {
+                    let parsed = Score::from_string(value);
+                    if parsed == Score::Unknown && value != Score::Unknown.to_word() {
+                        return None;
+                    }
+                    score = Some(parsed);
+                }
+                "best move" => best_move = Some(value.parse::<u32>().ok()?),
+                _ => return None,
+            }
+        }
+
+        let position = Position::from_string(&diagram).ok()?;
+        if !Position::is_valid_position_code(position.to_position_code()) {
+            return None;
+        }
+        let expected_to_move = if position.get_ply() % 2 == 0 {
+            "X"
         } else {
-            Some(BookEntry::new(&position, score))
+            "O"
+        };
+        let variation_matches = |variation: &str| {
+            variation == "?" || Position::from_variation(variation).is_ok_and(|v| v == position)
+        };
+        if code.is_some_and(|code| code != position.to_position_code())
+            || variation.is_some_and(|variation| !variation_matches(variation))
+            || ply.is_some_and(|ply| ply != position.get_ply())
+            || to_move.is_some_and(|to_move| to_move != expected_to_move)
+            || best_move.is_some_and(|x| x == 0 || x > BOARD_WIDTH)
+        {
+            return None;
         }
+        Some(BookEntry::annotated(
+            &position,
+            score?,
+            best_move.map(|x| x - 1),
+        ))
     }
 
     fn autodetect_parse(line: &str) -> Option<BookEntry> {
@@ -120,17 +384,45 @@ impl BookEntry {
             board <<= 8;
             board |= *byte as u64;
         }
-        Some(BookEntry(board))
+        let entry = BookEntry(board);
+        if !Position::is_valid_position_code(entry.get_position_code()) {
+            return None;
+        }
+        // makes sure that the annotations are in range
+        entry
+            .with_annotations(None)
+            .with_annotation_bits(entry.get_annotation_bits())
     }
 
     /// Two u64s are saved. The first u64 is always for the first player (i.e. so not necessarily
     /// the current player). Both bitboards have been shifted left by 2 to make room for a score.
-    pub fn from_vianiato_bytes(bytes: &[u8; 16]) -> BookEntry {
-        let first_bytes: [u8; 8] = bytes[0..8].try_into().unwrap();
-        let second_bytes: [u8; 8] = bytes[8..16].try_into().unwrap();
+    /// This layout is the one that the reader and writer of this crate have always used, but they
+    /// left the score bits as zero and there is no published specification of them. The values
+    /// are therefore a convention of this crate, and books written before it read as unknown.
+    ///
+    /// The score is from the first player's perspective. The low bits of the first u64 are 0 for
+    /// an unknown score, 1 for a loss, 2 for a draw and 3 for a win. The low bits of the second
+    /// u64 turn a draw into a bound: 1 for a draw or loss and 2 for a draw or win. Returns None if
+    /// the bits do not form a valid entry.
+    pub fn from_vianiato_bytes(bytes: &[u8; 16]) -> Option<BookEntry> {
+        let first_word = u64::from_be_bytes(bytes[0..8].try_into().unwrap());
+        let second_word = u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+
+        let first_board = Bitboard(first_word >> 2);
+        let second_board = Bitboard(second_word >> 2);
+        if first_board.0 & second_board.0 != 0 {
+            return None;
+        }
 
-        let first_board = Bitboard(u64::from_be_bytes(first_bytes) >> 2);
-        let second_board = Bitboard(u64::from_be_bytes(second_bytes) >> 2);
+        let mut score = match (first_word & 3, second_word & 3) {
+            (0, 0) => Score::Unknown,
+            (1, 0) => Score::Loss,
+            (2, 0) => Score::Draw,
+            (3, 0) => Score::Win,
+            (2, 1) => Score::DrawOrLoss,
+            (2, 2) => Score::DrawOrWin,
+            _ => return None,
+        };
 
         let mut position = Position {
             current: first_board,
@@ -141,22 +433,35 @@ impl BookEntry {
             position = Position {
                 current: second_board,
                 other: first_board,
-            }
+            };
+            score = score.flip();
         }
 
-        BookEntry::new(&position, Score::Unknown)
+        let code = position.to_position_code();
+        if !Position::is_valid_position_code(code) || code > Self::POSITION_MASK {
+            return None;
+        }
+        Some(BookEntry::new(&position, score))
     }
 
     pub fn to_vianiato_bytes(&self) -> [u8; 16] {
         let position = self.get_position();
-        let (first, second) = if position.get_ply() % 2 == 0 {
-            (position.current, position.other)
+        let (first, second, score) = if position.get_ply() % 2 == 0 {
+            (position.current, position.other, self.get_score())
         } else {
-            (position.other, position.current)
+            (position.other, position.current, self.get_score().flip())
         };
 
-        let first_bytes = (first.0 << 2).to_be_bytes();
-        let second_bytes = (second.0 << 2).to_be_bytes();
+        let (first_score_bits, second_score_bits) = match score {
+            Score::Unknown => (0, 0),
+            Score::Loss => (1, 0),
+            Score::Draw => (2, 0),
+            Score::Win => (3, 0),
+            Score::DrawOrLoss => (2, 1),
+            Score::DrawOrWin => (2, 2),
+        };
+        let first_bytes = (first.0 << 2 | first_score_bits).to_be_bytes();
+        let second_bytes = (second.0 << 2 | second_score_bits).to_be_bytes();
 
         let mut result = [0u8; 16];
         result[0..8].copy_from_slice(&first_bytes);
@@ -177,49 +482,281 @@ impl PartialOrd for BookEntry {
     }
 }
 
+/// Book entries sorted by position code
+enum Entries {
+    /// Entries that have been parsed or added one at a time
+    Parsed(Vec<BookEntry>),
+    /// The entries of a sorted versioned book as words in their on-disk byte order. They are
+    /// searched in place so loading such a book does not need any sorting, and a lookup only swaps
+    /// the bytes of the words that it compares.
+    Raw(Vec<BoardInteger>),
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        match self {
+            Entries::Parsed(entries) => entries.len(),
+            Entries::Raw(words) => words.len(),
+        }
+    }
+
+    #[inline]
+    fn get(&self, index: usize) -> BookEntry {
+        match self {
+            Entries::Parsed(entries) => entries[index],
+            Entries::Raw(words) => BookEntry(BoardInteger::from_be(words[index])),
+        }
+    }
+
+    fn binary_search(&self, entry: &BookEntry) -> Result<usize, usize> {
+        match self {
+            Entries::Parsed(entries) => entries.binary_search(entry),
+            Entries::Raw(words) => {
+                words.binary_search_by(|word| BookEntry(BoardInteger::from_be(*word)).cmp(entry))
+            }
+        }
+    }
+
+    /// Converts raw entries to parsed ones so that they can be modified
+    fn to_mut(&mut self) -> &mut Vec<BookEntry> {
+        if let Entries::Raw(words) = self {
+            let parsed = words
+                .iter()
+                .map(|word| BookEntry(BoardInteger::from_be(*word)))
+                .collect();
+            *self = Entries::Parsed(parsed);
+        }
+        match self {
+            Entries::Parsed(entries) => entries,
+            Entries::Raw { .. } => unreachable!(),
+        }
+    }
+}
+
+/// The entries of a book in a hash table with open addressing and linear probing, keyed by the
+/// position code. The table is at most half full so a lookup usually reads only one cache line,
+/// whereas binary search reads a different cache line at almost every step.
+struct HashIndex {
+    /// The length is a power of two. Empty slots are zero, which is never a valid entry because
+    /// every position code has a bit set for each column.
+    slots: Vec<BookEntry>,
+    /// How much the hash is shifted to get an index of the table
+    shift: u32,
+}
+
+impl HashIndex {
+    fn new(entries: &Entries) -> HashIndex {
+        let slot_count = (2 * entries.len()).next_power_of_two().max(2);
+        let mut index = HashIndex {
+            slots: vec![BookEntry(0); slot_count],
+            shift: u64::BITS - slot_count.trailing_zeros(),
+        };
+        for i in 0..entries.len() {
+            let entry = entries.get(i);
+            let mut slot = index.slot_of(entry.get_position_code());
+            while index.slots[slot].0 != 0 {
+                slot = (slot + 1) & (slot_count - 1);
+            }
+            index.slots[slot] = entry;
+        }
+        index
+    }
+
+    #[inline]
+    fn slot_of(&self, code: BoardInteger) -> usize {
+        // Fibonacci hashing spreads codes that differ only in their low bits across the table
+        (code.wrapping_mul(0x9E3779B97F4A7C15) >> self.shift) as usize
+    }
+
+    #[inline]
+    fn get(&self, code: BoardInteger) -> Option<BookEntry> {
+        let mask = self.slots.len() - 1;
+        let mut slot = self.slot_of(code);
+        loop {
+            let entry = self.slots[slot];
+            if entry.0 == 0 {
+                return None;
+            }
+            if entry.get_position_code() == code {
+                return Some(entry);
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+}
+
 pub struct Book {
-    entries: Vec<BookEntry>,
-    /// A bitwise union of the plies of positions stored in this book. It is recommended that books
-    /// contain plies that are powers of two (e.g. 4 and 8) so that the ply mask works efficiently.
-    ply_mask: u32,
+    entries: Entries,
+    /// Bit n is set if the book has positions of ply n. Every ply fits because a board has fewer
+    /// than 64 cells.
+    ply_set: u64,
+    /// A copy of the entries for faster lookups. See build_lookup_index.
+    lookup_index: OnceLock<HashIndex>,
 }
 
 impl Book {
     pub fn empty() -> Book {
         Book {
-            entries: vec![],
-            ply_mask: 0,
+            entries: Entries::Parsed(vec![]),
+            ply_set: 0,
+            lookup_index: OnceLock::new(),
         }
     }
 
+    /// Combines the books of the default plies. If only one of them exists, it is returned as it
+    /// was loaded so that a sorted versioned book stays in place instead of being copied.
     pub fn standard() -> Book {
-        let mut book = Book::empty();
-        book.include_book(&Self::ply(4));
-        book.include_book(&Self::ply(8));
+        let mut books = [Self::ply(4), Self::ply(8)]
+            .into_iter()
+            .filter(|book| !book.is_empty());
+        let mut book = books.next().unwrap_or_else(Book::empty);
+        for another_book in books {
+            book.include_book(&another_book);
+        }
         book
     }
 
+    /// Opens the book of the given ply from the default folder. A versioned book is preferred
+    /// because it loads faster.
     pub fn ply(ply: u32) -> Book {
-        let path = get_path_for_ply(ply);
+        let binary_path = get_versioned_path_for_ply(ply);
+        let path = if binary_path.exists() {
+            binary_path
+        } else {
+            get_path_for_ply(ply)
+        };
         Self::open(path.as_path()).unwrap_or_else(|_| Book::empty())
     }
 
+    /// Adds the entries of another book. Both books are already sorted so they are merged in
+    /// linear time.
     pub fn include_book(&mut self, another_book: &Book) {
+        if another_book.is_empty() {
+            return;
+        }
+
+        let entries = self.entries_mut();
+        let old_entries = mem::take(entries);
+        entries.reserve_exact(old_entries.len() + another_book.len());
+        let mut old_iter = old_entries.into_iter().peekable();
         for entry in another_book.iter() {
-            self.add_entry(*entry)
+            while let Some(old_entry) = old_iter.next_if(|old_entry| *old_entry <= entry) {
+                entries.push(old_entry);
+            }
+            entries.push(entry);
+        }
+        entries.extend(old_iter);
+        self.ply_set |= another_book.ply_set;
+    }
+
+    /// Compares the entries of two books in a single pass
+    pub fn diff(&self, another_book: &Book) -> BookDiff {
+        let mut diff = BookDiff {
+            conflicts: vec![],
+            only_in_first: vec![],
+            only_in_second: vec![],
+            shared_count: 0,
+        };
+        let mut iter1 = self.iter().peekable();
+        let mut iter2 = another_book.iter().peekable();
+        loop {
+            match (iter1.peek(), iter2.peek()) {
+                (Some(&entry1), Some(&entry2)) => match entry1.cmp(&entry2) {
+                    Ordering::Less => {
+                        diff.only_in_first.push(entry1);
+                        iter1.next();
+                    }
+                    Ordering::Greater => {
+                        diff.only_in_second.push(entry2);
+                        iter2.next();
+                    }
+                    Ordering::Equal => {
+                        diff.shared_count += 1;
+                        if entry1.get_score() != entry2.get_score() {
+                            diff.conflicts.push((entry1, entry2));
+                        }
+                        iter1.next();
+                        iter2.next();
+                    }
+                },
+                (Some(_), None) => diff.only_in_first.extend(iter1.by_ref()),
+                (None, Some(_)) => diff.only_in_second.extend(iter2.by_ref()),
+                (None, None) => break,
+            }
+        }
+        diff
+    }
+
+    /// Returns a book with the positions of both books. This book is the reference for the
+    /// conflict policy. Fails with Error::Conflict if some scores cannot be merged.
+    pub fn merge(&self, another_book: &Book, policy: ConflictPolicy) -> Result<Book, Error> {
+        let mut entries = Vec::with_capacity(self.len() + another_book.len());
+        let mut conflict_count = 0;
+        let mut iter1 = self.iter().peekable();
+        let mut iter2 = another_book.iter().peekable();
+        loop {
+            let entry = match (iter1.peek(), iter2.peek()) {
+                (Some(&entry1), Some(&entry2)) => match entry1.cmp(&entry2) {
+                    Ordering::Less => iter1.next(),
+                    Ordering::Greater => iter2.next(),
+                    Ordering::Equal => {
+                        iter1.next();
+                        iter2.next();
+                        match policy.merge(entry1.get_score(), entry2.get_score()) {
+                            // keeps the annotations of the entry whose score was chosen
+                            Some(score) if score == entry1.get_score() => Some(entry1),
+                            Some(score) if score == entry2.get_score() => Some(entry2),
+                            Some(score) => Some(entry1.with_score(score)),
+                            None => {
+                                conflict_count += 1;
+                                continue;
+                            }
+                        }
+                    }
+                },
+                (Some(_), None) => iter1.next(),
+                (None, Some(_)) => iter2.next(),
+                (None, None) => break,
+            };
+            entries.extend(entry);
+        }
+
+        if conflict_count > 0 {
+            return Err(Error::Conflict {
+                count: conflict_count,
+            });
         }
-        self.sort_and_shrink()
+        Ok(Book {
+            entries: Entries::Parsed(entries),
+            ply_set: self.ply_set | another_book.ply_set,
+            lookup_index: OnceLock::new(),
+        })
     }
 
     /// Reads an opening book by autodetecting its format
-    pub fn open(file_path: &Path) -> Result<Book, std::io::Error> {
+    pub fn open(file_path: &Path) -> Result<Book, Error> {
         let file = File::open(file_path)?;
-        let mut buf = BufReader::new(file);
-        match Self::read_text_book(&mut buf) {
+        Self::read_autodetect(&mut BufReader::new(file))
+    }
+
+    /// Reads an opening book from memory by autodetecting its format. Useful where there is no
+    /// file system, e.g. in WebAssembly.
+    pub fn from_bytes(data: &[u8]) -> Result<Book, Error> {
+        Self::read_autodetect(&mut BufReader::new(Cursor::new(data)))
+    }
+
+    fn read_autodetect<R: Read + Seek>(buf: &mut BufReader<R>) -> Result<Book, Error> {
+        if buf.fill_buf()?.starts_with(&VERSIONED_MAGIC) {
+            return Self::read_versioned_book(buf);
+        }
+        if buf.fill_buf()?.starts_with(b"code:") {
+            return Self::read_entries(BookReader::sequential(buf, BookFormat::Readable));
+        }
+        match Self::read_text_book(buf) {
             Ok(book) => Ok(book),
             Err(err) => {
                 buf.seek(SeekFrom::Start(0))?;
-                if let Ok(book) = Self::read_binary_book(&mut buf) {
+                if let Ok(book) = Self::read_binary_book(buf) {
                     Ok(book)
                 } else {
                     Err(err)
@@ -228,14 +765,28 @@ impl Book {
         }
     }
 
-    pub fn open_with_format(file_path: &Path, book_format: BookFormat) -> Result<Book, std::io::Error> {
+    /// Reads an opening book like `open` and, if requested, checks that every position in it can
+    /// occur in a real game
+    pub fn open_validated(file_path: &Path, validate: bool) -> Result<Book, Error> {
+        let book = Self::open(file_path)?;
+        if validate {
+            book.validate()?;
+        }
+        Ok(book)
+    }
+
+    pub fn open_with_format(file_path: &Path, book_format: BookFormat) -> Result<Book, Error> {
         let file = File::open(file_path)?;
         let mut buf = BufReader::new(file);
 
         match book_format {
             BookFormat::Binary => Self::read_binary_book(&mut buf),
             BookFormat::Hex => Self::read_text_book(&mut buf),
-            BookFormat::Vianiato => Self::read_vianiato_book(&mut buf)
+            BookFormat::Vianiato => Self::read_vianiato_book(&mut buf),
+            BookFormat::Versioned | BookFormat::Compressed => Self::read_versioned_book(&mut buf),
+            BookFormat::Csv(_) | BookFormat::Readable => {
+                Self::read_entries(BookReader::sequential(buf, book_format))
+            }
         }
     }
 
@@ -250,191 +801,1458 @@ impl Book {
     /// let book = Book::from_lines(&str).unwrap();
     /// assert_eq!(book.len(), 2);
     /// ```
-    pub fn from_lines(data: &str) -> Result<Book, std::io::Error> {
+    pub fn from_lines(data: &str) -> Result<Book, Error> {
         let mut reader = BufReader::new(data.as_bytes());
         Self::read_text_book(&mut reader)
     }
 
-    fn read_text_book<R: Read>(reader: &mut BufReader<R>) -> Result<Book, std::io::Error> {
+    fn read_text_book<R: Read>(reader: &mut BufReader<R>) -> Result<Book, Error> {
+        Self::read_entries(BookReader::sequential(reader, BookFormat::Hex))
+    }
+
+    fn read_binary_book<R: Read>(reader: &mut BufReader<R>) -> Result<Book, Error> {
+        Self::read_entries(BookReader::sequential(reader, BookFormat::Binary))
+    }
+
+    fn read_vianiato_book<R: Read>(reader: &mut BufReader<R>) -> Result<Book, Error> {
+        Self::read_entries(BookReader::sequential(reader, BookFormat::Vianiato))
+    }
+
+    fn read_entries<R: Read>(reader: BookReader<R>) -> Result<Book, Error> {
         let mut book = Book::empty();
-        for line in reader.lines() {
-            let line = line?;
-            if !line.trim().is_empty() {
-                if let Some(entry) = BookEntry::autodetect_parse(&line) {
-                    book.add_entry(entry);
-                } else {
-                    let err = std::io::Error::new(
-                        ErrorKind::InvalidData,
-                        format!("Invalid position when reading opening book: {}", line),
-                    );
-                    return Err(err);
-                }
-            }
+        for entry in reader {
+            book.add_entry(entry?);
         }
         book.sort_and_shrink();
         Ok(book)
     }
 
-    fn read_binary_book<R: Read>(reader: &mut BufReader<R>) -> Result<Book, std::io::Error> {
-        let mut book = Book::empty();
-        let mut buffer = [0; BookEntry::BYTE_COUNT];
+    /// Reads the whole book in one go. Sorted books are then kept in their on-disk layout and are
+    /// only checked, not parsed.
+    fn read_versioned_book<R: Read>(reader: &mut BufReader<R>) -> Result<Book, Error> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        if data.len() < VersionedHeader::SIZE + VersionedFooter::SIZE {
+            return Err(Error::Format("Versioned book is truncated".to_string()));
+        }
 
-        loop {
-            match reader.read_exact(&mut buffer) {
-                Ok(_) => {
-                    let entry = BookEntry::from_bytes(&buffer).ok_or_else(|| {
-                        std::io::Error::new(ErrorKind::InvalidData, "Invalid position")
-                    })?;
-                    book.add_entry(entry);
-                }
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
-            }
+        let (header_bytes, rest) = data.split_at(VersionedHeader::SIZE);
+        let (entry_bytes, footer_bytes) = rest.split_at(rest.len() - VersionedFooter::SIZE);
+        let header = VersionedHeader::from_bytes(header_bytes)?;
+        header.check_compatibility()?;
+        let footer = VersionedFooter::from_bytes(footer_bytes);
+
+        let hash = fnv1a(
+            FNV_OFFSET_BASIS,
+            &data[..data.len() - VersionedFooter::SIZE],
+        );
+        if footer.compute_checksum(hash) != footer.checksum {
+            return Err(Error::Format(
+                "Versioned book has an invalid checksum".to_string(),
+            ));
         }
 
-        book.sort_and_shrink();
-        Ok(book)
-    }
+        if header.is_compressed() {
+            let (entries, ply_set) = decode_compressed_entries(entry_bytes, footer.entry_count)?;
+            footer.check_ply_set(ply_set)?;
+            return Ok(Book {
+                entries: Entries::Parsed(entries),
+                ply_set,
+                lookup_index: OnceLock::new(),
+            });
+        }
 
-    fn read_vianiato_book<R: Read>(reader: &mut BufReader<R>) -> Result<Book, std::io::Error> {
-        let mut book = Book::empty();
-        let mut buffer = [0; 16];
+        if entry_bytes.len() % BookEntry::BYTE_COUNT != 0
+            || (entry_bytes.len() / BookEntry::BYTE_COUNT) as u64 != footer.entry_count
+        {
+            return Err(Error::Format(format!(
+                "Versioned book should have {} entries but its size does not match",
+                footer.entry_count
+            )));
+        }
 
-        loop {
-            match reader.read_exact(&mut buffer) {
-                Ok(_) => {
-                    let entry = BookEntry::from_vianiato_bytes(&buffer);
-                    book.add_entry(entry);
+        // every entry is checked even if the entries are then kept as raw bytes
+        let sorted = header.is_sorted();
+        let mut entries = vec![];
+        let mut words = vec![];
+        let mut ply_set = 0;
+        let mut previous: Option<BookEntry> = None;
+        for chunk in entry_bytes.chunks_exact(BookEntry::BYTE_COUNT) {
+            let bytes: [u8; BookEntry::BYTE_COUNT] = chunk.try_into().unwrap();
+            let entry = BookEntry::from_bytes(&bytes).ok_or_else(|| {
+                Error::Format(format!(
+                    "Invalid position when reading opening book: {:0>16X}",
+                    u64::from_be_bytes(bytes)
+                ))
+            })?;
+            ply_set |= 1 << entry.get_position().get_ply();
+            if sorted {
+                if previous.is_some_and(|previous| previous >= entry) {
+                    return Err(Error::Format(
+                        "Versioned book is marked as sorted but its entries are not".to_string(),
+                    ));
                 }
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
+                previous = Some(entry);
+                words.push(BoardInteger::from_ne_bytes(bytes));
+            } else {
+                entries.push(entry);
             }
         }
 
+        footer.check_ply_set(ply_set)?;
+
+        if sorted {
+            return Ok(Book {
+                entries: Entries::Raw(words),
+                ply_set,
+                lookup_index: OnceLock::new(),
+            });
+        }
+
+        let mut book = Book {
+            entries: Entries::Parsed(entries),
+            ply_set,
+            lookup_index: OnceLock::new(),
+        };
         book.sort_and_shrink();
         Ok(book)
     }
 
     fn add_entry(&mut self, entry: BookEntry) {
         let ply = entry.get_position().get_ply();
-        self.ply_mask |= ply;
-        self.entries.push(entry);
+        self.ply_set |= 1 << ply;
+        self.entries_mut().push(entry);
+    }
+
+    /// Entries must be modified through this so that the lookup index is not left out of date
+    fn entries_mut(&mut self) -> &mut Vec<BookEntry> {
+        self.lookup_index.take();
+        self.entries.to_mut()
     }
 
     fn sort_and_shrink(&mut self) {
-        self.entries.sort();
-        self.entries.shrink_to_fit();
+        let entries = self.entries_mut();
+        entries.sort();
+        entries.shrink_to_fit();
     }
 
     /// A fast check if there are any positions of the given ply in this book. This check is in the
     /// hot path of the engine so it must be kept as simple as possible.
     pub fn contains_ply(&self, ply: u32) -> bool {
-        self.ply_mask & ply != 0
+        self.ply_set & (1 << ply) != 0
     }
 
     pub fn get(&self, position: &Position) -> Score {
+        self.get_entry(position)
+            .map_or(Score::Unknown, |entry| entry.get_score())
+    }
+
+    /// Copies the entries into a layout that is faster to search than the sorted entries, which
+    /// pays off when the engine looks up positions during a search. The table has two slots for
+    /// each entry rounded up to a power of two, so it takes two to four times the memory of the
+    /// entries (2 MiB or about 2.9 times for the ply 8 book) in addition to the entries, which are
+    /// kept. The copy is dropped if the book is modified. Only the first call builds it, so a
+    /// shared book can be given an index by any of its users.
+    pub fn build_lookup_index(&self) {
+        self.lookup_index
+            .get_or_init(|| HashIndex::new(&self.entries));
+    }
+
+    pub fn has_lookup_index(&self) -> bool {
+        self.lookup_index.get().is_some()
+    }
+
+    /// Finds the entry of a position or its mirror image
+    pub fn get_entry(&self, position: &Position) -> Option<BookEntry> {
         let entry = BookEntry::new(position, Score::Unknown);
+        if let Some(index) = self.lookup_index.get() {
+            return index.get(entry.get_position_code());
+        }
         match self.entries.binary_search(&entry) {
-            Ok(index) => self.entries[index].get_score(),
-            Err(_) => Score::Unknown,
+            Ok(index) => Some(self.entries.get(index)),
+            Err(_) => None,
+        }
+    }
+
+    /// Returns the best column to play in the given position if the book has one. Unlike the
+    /// column in the entry, the column is mirrored if the position is the mirror image of the
+    /// normalized position.
+    pub fn best_move(&self, position: &Position) -> Option<u32> {
+        let entry = self.get_entry(position)?;
+        let x = entry.get_best_move()?;
+        if position.to_position_code() == entry.get_position_code() {
+            Some(x)
+        } else {
+            Some(BOARD_WIDTH - 1 - x)
         }
     }
 
+    /// Looks up the position after every legal move. Scores are from the perspective of the player
+    /// to move so the best move has the highest score. A move that ends the game is scored without
+    /// the book because books do not contain finished games.
+    pub fn probe_moves(&self, position: &Position) -> Vec<(u32, Score)> {
+        (0..BOARD_WIDTH)
+            .filter_map(|x| {
+                let child = position.position_after_drop(x)?;
+                let score = if child.other.has_won() {
+                    Score::Win
+                } else if child.get_ply() == BOARD_WIDTH * BOARD_HEIGHT {
+                    Score::Draw
+                } else {
+                    self.get(&child).flip()
+                };
+                Some((x, score))
+            })
+            .collect()
+    }
+
     pub fn len(&self) -> usize {
         self.entries.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.entries.is_empty()
+        self.entries.len() == 0
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &BookEntry> {
-        self.entries.iter()
+    /// Iterates the entries in ascending order of position codes
+    pub fn iter(&self) -> impl Iterator<Item = BookEntry> + '_ {
+        (0..self.entries.len()).map(|index| self.entries.get(index))
     }
 
-    pub fn to_position_set(&self) -> HashSet<Position> {
-        let mut set = HashSet::new();
-        for book_entry in self.iter() {
-            let position = book_entry.get_position();
-            let score = book_entry.get_score();
-            if score != Score::Unknown {
-                set.insert(position);
-            }
+    /// Writes the book into a file. The entries are sorted so a versioned book written this way
+    /// can be loaded without parsing it.
+    pub fn save(&self, file_path: &Path, format: BookFormat) -> Result<(), Error> {
+        let file = io::BufWriter::new(File::create(file_path)?);
+        let mut book_writer = BookWriter::create_sorted(file, format);
+        for entry in self.iter() {
+            book_writer.write_entry(&entry)?;
         }
-        set
+        book_writer.finish()?;
+        Ok(())
     }
-}
 
-pub enum BookFormat {
-    Hex,
-    Binary,
-    Vianiato
+    /// Fails on the first position that cannot occur in a real game
+    pub fn validate(&self) -> Result<(), Error> {
+        self.iter().try_for_each(|entry| entry.validate())
+    }
 }
 
-pub struct BookWriter<W: Write> {
-    format: BookFormat,
-    writer: W,
+/// The differences between two books. Entries are in ascending order of position codes.
+pub struct BookDiff {
+    /// Entries of both books for positions that have different scores
+    pub conflicts: Vec<(BookEntry, BookEntry)>,
+    pub only_in_first: Vec<BookEntry>,
+    pub only_in_second: Vec<BookEntry>,
+    /// The number of positions that both books have, including conflicts
+    pub shared_count: usize,
 }
 
-impl<W: Write> BookWriter<W> {
-    pub fn create(writer: W, format: BookFormat) -> BookWriter<W> {
-        BookWriter { format, writer }
-    }
+/// How to merge books that have different scores for the same position
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConflictPolicy {
+    /// Prefer an exact score over a bound that is compatible with it, e.g. Draw over DrawOrWin.
+    /// Incompatible scores are still an error.
+    PreferExact,
+    /// Any difference is an error
+    Fail,
+    /// Keep the score of the reference book, which is the book that was merged into
+    KeepReference,
+}
 
-    pub fn write_entry(&mut self, entry: &BookEntry) -> io::Result<()> {
-        match &self.format {
-            BookFormat::Hex => {
-                let line = entry.to_hex_string();
-                self.writer.write_all(line.as_bytes())?;
-                self.writer.write_all(b"\n")
+impl ConflictPolicy {
+    /// Returns None if the scores cannot be merged
+    fn merge(self, reference: Score, other: Score) -> Option<Score> {
+        if reference == other {
+            return Some(reference);
+        }
+        match self {
+            ConflictPolicy::Fail => None,
+            ConflictPolicy::KeepReference => Some(reference),
+            ConflictPolicy::PreferExact => {
+                if !reference.is_compatible(other) {
+                    None
+                } else if reference == Score::Unknown || other.is_exact() {
+                    Some(other)
+                } else if other == Score::Unknown || reference.is_exact() {
+                    Some(reference)
+                } else {
+                    // DrawOrWin and DrawOrLoss together
+                    Some(Score::Draw)
+                }
             }
-            BookFormat::Binary => self.writer.write_all(&entry.to_bytes()),
-            BookFormat::Vianiato => self.writer.write_all(&entry.to_vianiato_bytes())
         }
     }
 }
 
-pub fn generate_book(ply: u32, use_book: Option<&Path>) -> Result<(), std::io::Error> {
-    create_dir_all(BOOK_FOLDER)?;
-    let book_path = get_path_for_ply(ply);
+/// Combines the entries of the same position in entries that are sorted by position code, which is
+/// how ExternalSorter returns entries that have the same position but different scores or
+/// annotations. Scores are merged with the policy like in Book::merge, the earlier entry being the
+/// reference. Positions whose scores cannot be merged are left out and Error::Conflict is returned
+/// after the last entry. The positions can then be listed with conflicts.
+pub struct MergeDuplicates<I: Iterator<Item = Result<BookEntry, Error>>> {
+    entries: Peekable<I>,
+    policy: ConflictPolicy,
+    conflicts: Vec<Position>,
+    finished: bool,
+}
 
-    let set = find_positions_to_solve(ply);
-    let total_count = set.len();
-    println!(
-        "There are {} positions to solve. Saving book as {}",
-        total_count,
-        book_path.display()
-    );
+impl<I: Iterator<Item = Result<BookEntry, Error>>> MergeDuplicates<I> {
+    pub fn new(entries: I, policy: ConflictPolicy) -> Self {
+        MergeDuplicates {
+            entries: entries.peekable(),
+            policy,
+            conflicts: vec![],
+            finished: false,
+        }
+    }
 
-    let existing_book = Book::open(book_path.as_path()).unwrap_or_else(|_| Book::empty());
-    if !existing_book.is_empty() {
-        println!("Found {} existing positions", existing_book.len());
+    /// The positions whose scores could not be merged so far
+    pub fn conflicts(&self) -> &[Position] {
+        &self.conflicts
     }
+}
 
-    let mut total_benchmark = Benchmark::empty();
-    let mut solved = 0;
+impl<I: Iterator<Item = Result<BookEntry, Error>>> Iterator for MergeDuplicates<I> {
+    type Item = Result<BookEntry, Error>;
 
-    let mut engine = Engine::new();
-    if let Some(another_book_path) = use_book {
-        let another_book = Box::new(Book::open(another_book_path)?);
-        engine.set_book(another_book);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let group = match next_position_group(&mut self.entries) {
+                Ok(Some(group)) => group,
+                Ok(None) if !self.conflicts.is_empty() && !self.finished => {
+                    self.finished = true;
+                    let count = self.conflicts.len();
+                    return Some(Err(Error::Conflict { count }));
+                }
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+
+            let mut merged = Some(group[0]);
+            for other in &group[1..] {
+                merged = merged.and_then(|entry| {
+                    let score = self.policy.merge(entry.get_score(), other.get_score())?;
+                    // keeps the annotations of the entry whose score was chosen
+                    if score == other.get_score()
+                        && (score != entry.get_score() || !entry.has_annotations())
+                    {
+                        Some(*other)
+                    } else {
+                        Some(entry.with_score(score))
+                    }
+                });
+            }
+            match merged {
+                Some(entry) => return Some(Ok(entry)),
+                None => self.conflicts.push(group[0].get_position()),
+            }
+        }
     }
-    let file = File::create(book_path.as_path())?;
-    let mut book_writer = BookWriter::create(file, BookFormat::Hex);
+}
 
-    for (count, pos) in set.into_iter().enumerate() {
-        let existing_score = existing_book.get(&pos);
-        if existing_score != Score::Unknown {
-            book_writer.write_entry(&BookEntry::new(&pos, existing_score))?;
-            continue;
-        }
+/// Whose point of view the discs and the score of an entry are from
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Perspective {
+    /// Like the UCI Connect-4 dataset
+    FirstPlayer,
+    /// Like the other book formats
+    SideToMove,
+}
 
-        engine.set_position(pos);
-        let benchmark = Benchmark::run(&mut engine);
-        book_writer.write_entry(&BookEntry::new(&pos, benchmark.score))?;
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BookFormat {
+    Hex,
+    Binary,
+    Vianiato,
+    /// Binary entries between a header and a footer that describe the book. See VersionedHeader
+    /// and VersionedFooter.
+    Versioned,
+    /// Like Versioned but the entries are delta-encoded. See encode_compressed_entry.
+    Compressed,
+    /// One line of comma-separated cells per entry. See BookEntry::from_csv_string.
+    Csv(Perspective),
+    /// Records of several lines separated by blank lines. See BookEntry::to_readable_string.
+    Readable,
+}
 
-        total_benchmark = total_benchmark.add(&benchmark);
-        solved += 1;
+impl FromStr for BookFormat {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "hex" => Ok(BookFormat::Hex),
+            "binary" => Ok(BookFormat::Binary),
+            "vianiato" => Ok(BookFormat::Vianiato),
+            "versioned" => Ok(BookFormat::Versioned),
+            "compressed" => Ok(BookFormat::Compressed),
+            "csv" => Ok(BookFormat::Csv(Perspective::FirstPlayer)),
+            "csv-side-to-move" => Ok(BookFormat::Csv(Perspective::SideToMove)),
+            "readable" => Ok(BookFormat::Readable),
+            _ => Err(Error::UnknownFormat(name.to_string())),
+        }
+    }
+}
+
+/// The first bytes of every book in the versioned format
+const VERSIONED_MAGIC: [u8; 8] = *b"FOURBOOK";
+const VERSIONED_VERSION: u16 = 1;
+/// Set when the entries are in ascending order of position codes without duplicates
+const FLAG_SORTED: u16 = 1;
+/// Set when the entries are delta-encoded instead of stored as fixed-size integers
+const FLAG_COMPRESSED: u16 = 2;
+
+/// Describes what kind of entries a versioned book has. All numbers are big-endian like the entries
+/// themselves.
+struct VersionedHeader {
+    version: u16,
+    board_width: u8,
+    board_height: u8,
+    score_bits: u8,
+    /// Reserved for books where scores are not from the perspective of the player to move
+    perspective: u8,
+    flags: u16,
+}
+
+impl VersionedHeader {
+    const SIZE: usize = 16;
+
+    fn current(flags: u16) -> VersionedHeader {
+        VersionedHeader {
+            version: VERSIONED_VERSION,
+            board_width: BOARD_WIDTH as u8,
+            board_height: BOARD_HEIGHT as u8,
+            score_bits: SCORE_BITS as u8,
+            perspective: 0,
+            flags,
+        }
+    }
+
+    fn is_sorted(&self) -> bool {
+        self.flags & FLAG_SORTED != 0
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..8].copy_from_slice(&VERSIONED_MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_be_bytes());
+        bytes[10] = self.board_width;
+        bytes[11] = self.board_height;
+        bytes[12] = self.score_bits;
+        bytes[13] = self.perspective;
+        bytes[14..16].copy_from_slice(&self.flags.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<VersionedHeader, Error> {
+        if bytes.len() < Self::SIZE || bytes[0..8] != VERSIONED_MAGIC {
+            return Err(Error::Format("Not a versioned book".to_string()));
+        }
+        Ok(VersionedHeader {
+            version: u16::from_be_bytes([bytes[8], bytes[9]]),
+            board_width: bytes[10],
+            board_height: bytes[11],
+            score_bits: bytes[12],
+            perspective: bytes[13],
+            flags: u16::from_be_bytes([bytes[14], bytes[15]]),
+        })
+    }
+
+    fn check_compatibility(&self) -> Result<(), Error> {
+        if self.version != VERSIONED_VERSION {
+            return Err(Error::Format(format!(
+                "Unsupported book version {}",
+                self.version
+            )));
+        }
+        if self.board_width as u32 != BOARD_WIDTH || self.board_height as u32 != BOARD_HEIGHT {
+            return Err(Error::Format(format!(
+                "Book is for a {}x{} board but the engine uses {}x{}",
+                self.board_width, self.board_height, BOARD_WIDTH, BOARD_HEIGHT
+            )));
+        }
+        if self.score_bits as u32 != SCORE_BITS || self.perspective != 0 {
+            return Err(Error::Format(
+                "Book uses unsupported score semantics".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Summarizes the entries of a versioned book. It is written after the entries so that books can be
+/// written in a single pass without knowing the entries beforehand.
+struct VersionedFooter {
+    /// Bit n is set if the book has positions of ply n
+    ply_set: u64,
+    entry_count: u64,
+    /// FNV-1a hash of every byte of the book before the checksum, so the header and the other
+    /// footer fields are covered as well as the entries
+    checksum: u64,
+}
+
+impl VersionedFooter {
+    const SIZE: usize = 24;
+
+    fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.ply_set.to_be_bytes());
+        bytes[8..16].copy_from_slice(&self.entry_count.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.checksum.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> VersionedFooter {
+        let read_u64 =
+            |start: usize| u64::from_be_bytes(bytes[start..start + 8].try_into().unwrap());
+        VersionedFooter {
+            ply_set: read_u64(0),
+            entry_count: read_u64(8),
+            checksum: read_u64(16),
+        }
+    }
+
+    /// Finishes a hash of the header and entries by adding the footer fields before the checksum
+    fn compute_checksum(&self, hash: u64) -> u64 {
+        fnv1a(hash, &self.to_bytes()[..16])
+    }
+
+    /// Fails if the plies of the entries are not the ones that the footer claims
+    fn check_ply_set(&self, ply_set: u64) -> Result<(), Error> {
+        if ply_set != self.ply_set {
+            return Err(Error::Format(
+                "Versioned book has plies that do not match its footer".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Continues a 64-bit FNV-1a hash. Start with FNV_OFFSET_BASIS.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Encodes an entry as the difference to the previous position code, shifted left to make room for
+/// an annotation flag and the score, and writes the result as a LEB128 variable-length integer. If
+/// the flag is set, the annotations follow as another integer. Sorted position codes are close to
+/// each other so most entries take only a few bytes.
+fn encode_compressed_entry(entry: &BookEntry, previous_code: BoardInteger, out: &mut Vec<u8>) {
+    let delta = entry.get_position_code() - previous_code;
+    let annotated = entry.has_annotations() as u64;
+    write_varint(
+        (delta << (SCORE_BITS + 1)) | annotated << SCORE_BITS | entry.get_score() as u64,
+        out,
+    );
+    if entry.has_annotations() {
+        write_varint(entry.get_annotation_bits(), out);
+    }
+}
+
+fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(next_byte: &mut impl FnMut() -> Option<u8>) -> Option<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = next_byte()?;
+        if shift >= u64::BITS {
+            return None;
+        }
+        value |= ((byte & 0x7F) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+/// Decodes the entry that follows the one with the previous position code, or the first entry if
+/// there is no previous one
+fn decode_compressed_entry(
+    next_byte: &mut impl FnMut() -> Option<u8>,
+    previous_code: Option<BoardInteger>,
+) -> Result<BookEntry, Error> {
+    let invalid = || Error::Format("Invalid data in compressed book".to_string());
+    let value = read_varint(next_byte).ok_or_else(invalid)?;
+    let delta = value >> (SCORE_BITS + 1);
+    // every entry except the first must have a greater position code than the previous one
+    if delta == 0 && previous_code.is_some() {
+        return Err(invalid());
+    }
+    let code = previous_code
+        .unwrap_or(0)
+        .checked_add(delta)
+        .ok_or_else(invalid)?;
+    if !Position::is_valid_position_code(code) || code > BookEntry::POSITION_MASK {
+        return Err(invalid());
+    }
+    let score = Score::from_u64_fast(value & ((1 << SCORE_BITS) - 1));
+    let entry = BookEntry(code | (score as u64) << BookEntry::SCORE_SHIFT);
+    if value & (1 << SCORE_BITS) != 0 {
+        let bits = read_varint(next_byte).ok_or_else(invalid)?;
+        return entry.with_annotation_bits(bits).ok_or_else(invalid);
+    }
+    Ok(entry)
+}
+
+/// Returns the entries together with the plies that they have
+fn decode_compressed_entries(
+    bytes: &[u8],
+    entry_count: u64,
+) -> Result<(Vec<BookEntry>, u64), Error> {
+    let mut entries: Vec<BookEntry> = Vec::with_capacity(entry_count as usize);
+    let mut ply_set = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        let mut next_byte = || {
+            let byte = bytes.get(index).copied();
+            index += 1;
+            byte
+        };
+        let previous_code = entries.last().map(|entry| entry.get_position_code());
+        let entry = decode_compressed_entry(&mut next_byte, previous_code)?;
+        ply_set |= 1 << entry.get_position().get_ply();
+        entries.push(entry);
+    }
+
+    if entries.len() as u64 != entry_count {
+        return Err(Error::Format(format!(
+            "Compressed book should have {} entries but it has {}",
+            entry_count,
+            entries.len()
+        )));
+    }
+    Ok((entries, ply_set))
+}
+
+/// Writes entries one at a time. Call finish after the last entry because some formats need to
+/// write a footer.
+pub struct BookWriter<W: Write> {
+    format: BookFormat,
+    writer: W,
+    /// If set, entries must be written in ascending order of position codes
+    sorted: bool,
+    previous_code: Option<BoardInteger>,
+    header_written: bool,
+    footer: VersionedFooter,
+}
+
+impl<W: Write> BookWriter<W> {
+    /// Creates a writer that accepts entries in any order, except for the compressed format which
+    /// always requires ascending order
+    pub fn create(writer: W, format: BookFormat) -> BookWriter<W> {
+        let sorted = matches!(format, BookFormat::Compressed);
+        BookWriter {
+            format,
+            writer,
+            sorted,
+            previous_code: None,
+            header_written: false,
+            footer: VersionedFooter {
+                ply_set: 0,
+                entry_count: 0,
+                checksum: FNV_OFFSET_BASIS,
+            },
+        }
+    }
+
+    /// Creates a writer that only accepts entries in ascending order of position codes. Versioned
+    /// books written this way are marked as sorted so that they load without parsing.
+    pub fn create_sorted(writer: W, format: BookFormat) -> BookWriter<W> {
+        BookWriter {
+            sorted: true,
+            ..Self::create(writer, format)
+        }
+    }
+
+    pub fn write_entry(&mut self, entry: &BookEntry) -> Result<(), Error> {
+        let previous_code = self.previous_code;
+        if self.sorted {
+            let code = entry.get_position_code();
+            if previous_code.is_some_and(|previous| previous >= code) {
+                return Err(Error::Format(format!(
+                    "Entries are not in ascending order at position {:0>16X}",
+                    code
+                )));
+            }
+            self.previous_code = Some(code);
+        }
+
+        match &self.format {
+            BookFormat::Hex => {
+                let line = entry.to_hex_string();
+                self.writer.write_all(line.as_bytes())?;
+                self.writer.write_all(b"\n")?;
+            }
+            BookFormat::Csv(perspective) => {
+                let line = entry.to_csv_string(*perspective);
+                self.writer.write_all(line.as_bytes())?;
+                self.writer.write_all(b"\n")?;
+            }
+            BookFormat::Readable => {
+                let record = entry.to_readable_string();
+                self.writer.write_all(record.as_bytes())?;
+                self.writer.write_all(b"\n")?;
+            }
+            BookFormat::Binary => self.writer.write_all(&entry.to_bytes())?,
+            BookFormat::Vianiato => self.writer.write_all(&entry.to_vianiato_bytes())?,
+            BookFormat::Versioned => {
+                self.write_versioned_bytes(entry, &entry.to_bytes())?;
+            }
+            BookFormat::Compressed => {
+                let mut bytes = Vec::with_capacity(10);
+                encode_compressed_entry(entry, previous_code.unwrap_or(0), &mut bytes);
+                self.write_versioned_bytes(entry, &bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_versioned_bytes(&mut self, entry: &BookEntry, bytes: &[u8]) -> Result<(), Error> {
+        self.write_header()?;
+        self.writer.write_all(bytes)?;
+        self.footer.ply_set |= 1 << entry.get_position().get_ply();
+        self.footer.entry_count += 1;
+        self.footer.checksum = fnv1a(self.footer.checksum, bytes);
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<(), Error> {
+        if !self.header_written {
+            let mut flags = 0;
+            if self.sorted {
+                flags |= FLAG_SORTED;
+            }
+            if let BookFormat::Compressed = self.format {
+                flags |= FLAG_COMPRESSED;
+            }
+            let header_bytes = VersionedHeader::current(flags).to_bytes();
+            self.writer.write_all(&header_bytes)?;
+            self.footer.checksum = fnv1a(self.footer.checksum, &header_bytes);
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Writes what remains after the last entry, flushes and returns the underlying writer
+    pub fn finish(mut self) -> Result<W, Error> {
+        if let BookFormat::Versioned | BookFormat::Compressed = self.format {
+            self.write_header()?;
+            self.footer.checksum = self.footer.compute_checksum(self.footer.checksum);
+            self.writer.write_all(&self.footer.to_bytes())?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads entries one at a time in the order of the file, so unlike Book it can process books that
+/// do not fit in memory. The entries are neither sorted nor deduplicated. Errors in entries are
+/// wrapped in Error::InBook to tell where the entry is, and iteration stops after the first error.
+pub struct BookReader<R: Read> {
+    reader: BufReader<R>,
+    format: BookFormat,
+    path: Option<PathBuf>,
+    versioned: Option<VersionedState>,
+    line: String,
+    /// The number of lines read from a text book
+    line_number: usize,
+    /// The line where the entry that was read last starts in a text book
+    entry_line: usize,
+    /// The number of bytes read from a binary book
+    offset: u64,
+    finished: bool,
+}
+
+/// What is needed to check the entries of a versioned book while they are read
+struct VersionedState {
+    footer: VersionedFooter,
+    sorted: bool,
+    /// The number of entry bytes left before the footer
+    remaining: u64,
+    entry_count: u64,
+    ply_set: u64,
+    checksum: u64,
+    previous_code: Option<BoardInteger>,
+}
+
+impl BookReader<File> {
+    /// Opens a book by autodetecting its format like Book::open
+    pub fn open(file_path: &Path) -> Result<Self, Error> {
+        Ok(Self::autodetect(File::open(file_path)?)?.with_path(file_path))
+    }
+
+    pub fn open_with_format(file_path: &Path, format: BookFormat) -> Result<Self, Error> {
+        Ok(Self::new(File::open(file_path)?, format)?.with_path(file_path))
+    }
+
+    fn with_path(self, file_path: &Path) -> Self {
+        BookReader {
+            path: Some(file_path.to_path_buf()),
+            ..self
+        }
+    }
+}
+
+impl<R: Read + Seek> BookReader<R> {
+    /// Versioned and compressed books are told apart by their header so either format works for
+    /// both
+    pub fn new(reader: R, format: BookFormat) -> Result<Self, Error> {
+        let mut book_reader = Self::sequential(reader, format);
+        if let BookFormat::Versioned | BookFormat::Compressed = format {
+            book_reader.read_versioned_header()?;
+        }
+        Ok(book_reader)
+    }
+
+    /// Detects the versioned formats by their header and the readable format by its first field.
+    /// Otherwise the book is read as text if its first line is a valid entry and as binary if not.
+    pub fn autodetect(reader: R) -> Result<Self, Error> {
+        let mut buf = BufReader::new(reader);
+        let bytes = buf.fill_buf()?;
+        let format = if bytes.starts_with(&VERSIONED_MAGIC) {
+            BookFormat::Versioned
+        } else {
+            let first_line = bytes
+                .split(|byte| *byte == b'\n')
+                .map(|line| std::str::from_utf8(line).map(str::trim))
+                .find(|line| line.is_err() || line.is_ok_and(|line| !line.is_empty()));
+            match first_line {
+                Some(Ok(line)) if line.starts_with("code:") => BookFormat::Readable,
+                Some(Ok(line)) if BookEntry::autodetect_parse(line).is_none() => BookFormat::Binary,
+                Some(Err(_)) => BookFormat::Binary,
+                _ => BookFormat::Hex,
+            }
+        };
+        buf.seek(SeekFrom::Start(0))?;
+        Self::new(buf.into_inner(), format)
+    }
+
+    fn read_versioned_header(&mut self) -> Result<(), Error> {
+        let truncated = || Error::Format("Versioned book is truncated".to_string());
+        let mut header_bytes = [0u8; VersionedHeader::SIZE];
+        self.reader
+            .read_exact(&mut header_bytes)
+            .map_err(|_| truncated())?;
+        let header = VersionedHeader::from_bytes(&header_bytes)?;
+        header.check_compatibility()?;
+
+        let length = self.reader.seek(SeekFrom::End(0))?;
+        let remaining = length
+            .checked_sub((VersionedHeader::SIZE + VersionedFooter::SIZE) as u64)
+            .ok_or_else(truncated)?;
+        let mut footer_bytes = [0u8; VersionedFooter::SIZE];
+        self.reader
+            .seek(SeekFrom::End(-(VersionedFooter::SIZE as i64)))?;
+        self.reader.read_exact(&mut footer_bytes)?;
+        self.reader
+            .seek(SeekFrom::Start(VersionedHeader::SIZE as u64))?;
+
+        self.format = if header.is_compressed() {
+            BookFormat::Compressed
+        } else {
+            BookFormat::Versioned
+        };
+        self.offset = VersionedHeader::SIZE as u64;
+        self.versioned = Some(VersionedState {
+            footer: VersionedFooter::from_bytes(&footer_bytes),
+            sorted: header.is_sorted(),
+            remaining,
+            entry_count: 0,
+            ply_set: 0,
+            checksum: fnv1a(FNV_OFFSET_BASIS, &header_bytes),
+            previous_code: None,
+        });
+        Ok(())
+    }
+}
+
+impl<R: Read> BookReader<R> {
+    /// Reads a format that has no header or footer from start to end, so the reader does not need
+    /// to be seekable
+    fn sequential(reader: R, format: BookFormat) -> Self {
+        BookReader {
+            reader: BufReader::new(reader),
+            format,
+            path: None,
+            versioned: None,
+            line: String::new(),
+            line_number: 0,
+            entry_line: 0,
+            offset: 0,
+            finished: false,
+        }
+    }
+
+    fn read_entry(&mut self) -> Result<Option<BookEntry>, Error> {
+        match self.format {
+            BookFormat::Hex => self.read_text_entry(BookEntry::autodetect_parse),
+            BookFormat::Csv(perspective) => {
+                self.read_text_entry(|line| BookEntry::from_csv_string(line, perspective))
+            }
+            BookFormat::Readable => self.read_readable_entry(),
+            BookFormat::Binary => Ok(self
+                .read_fixed_size::<{ BookEntry::BYTE_COUNT }>()?
+                .map(|bytes| BookEntry::from_bytes(&bytes).ok_or_else(|| invalid_bytes(bytes)))
+                .transpose()?),
+            BookFormat::Vianiato => Ok(self
+                .read_fixed_size::<16>()?
+                .map(|bytes| {
+                    BookEntry::from_vianiato_bytes(&bytes).ok_or_else(|| {
+                        Error::Format(format!(
+                            "Invalid Vianiato entry when reading opening book: {:0>32X}",
+                            u128::from_be_bytes(bytes)
+                        ))
+                    })
+                })
+                .transpose()?),
+            BookFormat::Versioned | BookFormat::Compressed => self.read_versioned_entry(),
+        }
+    }
+
+    fn read_text_entry<F>(&mut self, parse: F) -> Result<Option<BookEntry>, Error>
+    where
+        F: Fn(&str) -> Option<BookEntry>,
+    {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            self.entry_line = self.line_number;
+            // like BufRead::lines
+            let line = self.line.trim_end_matches('\n').trim_end_matches('\r');
+            if !line.trim().is_empty() {
+                return match parse(line) {
+                    Some(entry) => Ok(Some(entry)),
+                    None => Err(Error::Format(format!(
+                        "Invalid position when reading opening book: {}",
+                        line
+                    ))),
+                };
+            }
+        }
+    }
+
+    /// Reads the lines of a record until a blank line or the end of the file
+    fn read_readable_entry(&mut self) -> Result<Option<BookEntry>, Error> {
+        let mut record = String::new();
+        loop {
+            self.line.clear();
+            let end = self.reader.read_line(&mut self.line)? == 0;
+            let line = self.line.trim_end_matches('\n').trim_end_matches('\r');
+            if end || line.trim().is_empty() {
+                if end && record.is_empty() {
+                    return Ok(None);
+                }
+                if !end {
+                    self.line_number += 1;
+                }
+                if record.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            self.line_number += 1;
+            if record.is_empty() {
+                self.entry_line = self.line_number;
+            }
+            record.push_str(line);
+            record.push('\n');
+        }
+        BookEntry::from_readable_string(&record)
+            .map(Some)
+            .ok_or_else(|| Error::Format("Invalid record when reading opening book".to_string()))
+    }
+
+    /// Returns None at the end of the file and fails if the file ends in the middle of an entry
+    fn read_fixed_size<const N: usize>(&mut self) -> Result<Option<[u8; N]>, Error> {
+        let mut bytes = [0u8; N];
+        let mut count = 0;
+        while count < N {
+            match self.reader.read(&mut bytes[count..]) {
+                Ok(0) => break,
+                Ok(read_count) => count += read_count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if count == 0 {
+            Ok(None)
+        } else if count < N {
+            Err(Error::Format(format!(
+                "Book ends after {} bytes of an entry of {} bytes",
+                count, N
+            )))
+        } else {
+            self.offset += N as u64;
+            Ok(Some(bytes))
+        }
+    }
+
+    fn read_versioned_entry(&mut self) -> Result<Option<BookEntry>, Error> {
+        let state = self.versioned.as_mut().unwrap();
+        if state.remaining == 0 {
+            if state.footer.compute_checksum(state.checksum) != state.footer.checksum {
+                return Err(Error::Format(
+                    "Versioned book has an invalid checksum".to_string(),
+                ));
+            }
+            if state.entry_count != state.footer.entry_count {
+                return Err(Error::Format(format!(
+                    "Versioned book should have {} entries but it has {}",
+                    state.footer.entry_count, state.entry_count
+                )));
+            }
+            state.footer.check_ply_set(state.ply_set)?;
+            return Ok(None);
+        }
+
+        let reader = &mut self.reader;
+        let offset = &mut self.offset;
+        let mut io_error = None;
+        let mut next_byte = || {
+            if state.remaining == 0 {
+                return None;
+            }
+            let mut byte = [0u8];
+            if let Err(e) = reader.read_exact(&mut byte) {
+                io_error = Some(e);
+                return None;
+            }
+            state.remaining -= 1;
+            state.checksum = fnv1a(state.checksum, &byte);
+            *offset += 1;
+            Some(byte[0])
+        };
+
+        let entry = if self.format == BookFormat::Compressed {
+            decode_compressed_entry(&mut next_byte, state.previous_code)
+        } else {
+            let mut bytes = [0u8; BookEntry::BYTE_COUNT];
+            for byte in bytes.iter_mut() {
+                *byte = next_byte().ok_or_else(|| {
+                    Error::Format(format!(
+                        "Versioned book should have {} entries but its size does not match",
+                        state.footer.entry_count
+                    ))
+                })?;
+            }
+            BookEntry::from_bytes(&bytes).ok_or_else(|| invalid_bytes(bytes))
+        };
+        if let Some(e) = io_error {
+            return Err(e.into());
+        }
+        let entry = entry?;
+
+        if state.sorted
+            && state
+                .previous_code
+                .is_some_and(|previous| previous >= entry.get_position_code())
+        {
+            return Err(Error::Format(
+                "Versioned book is marked as sorted but its entries are not".to_string(),
+            ));
+        }
+        state.previous_code = Some(entry.get_position_code());
+        state.entry_count += 1;
+        state.ply_set |= 1 << entry.get_position().get_ply();
+        Ok(Some(entry))
+    }
+}
+
+impl<R: Read> Iterator for BookReader<R> {
+    type Item = Result<BookEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let start_offset = self.offset;
+        match self.read_entry().transpose() {
+            Some(Ok(entry)) => Some(Ok(entry)),
+            Some(Err(err)) => {
+                self.finished = true;
+                // a text entry starts on the line where it was read from whereas a binary entry
+                // starts where the reader was before reading it, or it is the end of a versioned
+                // book
+                let location = match self.format {
+                    BookFormat::Hex | BookFormat::Csv(_) | BookFormat::Readable => {
+                        BookLocation::Line(self.entry_line)
+                    }
+                    _ => BookLocation::Offset(start_offset),
+                };
+                Some(Err(Error::InBook {
+                    path: self.path.clone(),
+                    location,
+                    source: Box::new(err),
+                }))
+            }
+            None => {
+                self.finished = true;
+                None
+            }
+        }
+    }
+}
+
+fn invalid_bytes(bytes: [u8; BookEntry::BYTE_COUNT]) -> Error {
+    Error::Format(format!(
+        "Invalid position when reading opening book: {:0>16X}",
+        u64::from_be_bytes(bytes)
+    ))
+}
+
+/// Solves a position and then its children from left to right until one of them has the same
+/// score, so the first column with the best score is chosen without solving the other children
+fn solve_annotated(engine: &mut Engine, position: &Position) -> (BookEntry, Benchmark) {
+    engine.set_position(*position);
+    let mut total_benchmark = Benchmark::run(engine);
+    let score = total_benchmark.score;
+    for x in 0..BOARD_WIDTH {
+        if let Some(child) = position.position_after_drop(x) {
+            engine.set_position(child);
+            let benchmark = Benchmark::run(engine);
+            total_benchmark = total_benchmark.add(&benchmark);
+            if benchmark.score.flip() == score {
+                return (
+                    BookEntry::annotated(position, score, Some(x)),
+                    total_benchmark,
+                );
+            }
+        }
+    }
+    (BookEntry::new(position, score), total_benchmark)
+}
+
+/// The ply of the positions that generate_book solves
+#[derive(Copy, Clone, Debug)]
+pub enum PlyTarget {
+    Absolute(u32),
+    /// The number of plies after each root position
+    Relative(u32),
+}
+
+/// A part of the positions that generate_book solves so that the work can be split between
+/// processes or machines. Shard i of n solves every n-th position starting from the i-th one, in
+/// the order of the sorted position set, so the shards do not overlap and have similar amounts of
+/// work. The index is one-based.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Shard {
+    pub index: u32,
+    pub count: u32,
+}
+
+impl Shard {
+    fn contains(&self, position_index: usize) -> bool {
+        position_index % self.count as usize == (self.index - 1) as usize
+    }
+
+    /// How many of all the positions belong to this shard
+    fn get_position_count(&self, total_count: usize) -> usize {
+        (total_count + (self.count - self.index) as usize) / self.count as usize
+    }
+
+    /// Adds the shard to the file name, e.g. 7x6-ply8.txt becomes 7x6-ply8-shard1of4.txt
+    fn get_path(&self, book_path: &Path) -> PathBuf {
+        let stem = book_path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut file_name = format!("{}-shard{}of{}", stem, self.index, self.count);
+        if let Some(extension) = book_path.extension() {
+            file_name = format!("{}.{}", file_name, extension.to_string_lossy());
+        }
+        book_path.with_file_name(file_name)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = Error;
+
+    /// Parses "i/n", e.g. "1/4"
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            Error::Format(format!(
+                "Invalid shard {}. Expected i/n where 1 <= i <= n",
+                str
+            ))
+        };
+        let (index, count) = str.split_once('/').ok_or_else(invalid)?;
+        let index: u32 = index.trim().parse().map_err(|_| invalid())?;
+        let count: u32 = count.trim().parse().map_err(|_| invalid())?;
+        if index == 0 || index > count {
+            return Err(invalid());
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+/// Options for generate_book
+pub struct GenerateOptions<'a> {
+    /// Only positions that can be reached from these positions are solved. The standard books have
+    /// only the empty position as their root.
+    pub roots: Vec<Position>,
+    pub ply: PlyTarget,
+    /// Defaults to the path of the standard book for the ply
+    pub out: Option<&'a Path>,
+    /// Another book that the engine uses when solving positions
+    pub use_book: Option<&'a Path>,
+    pub validate: bool,
+    pub annotate: bool,
+    /// Solves only a part of the positions. Defaults to all of them.
+    pub shard: Option<Shard>,
+    /// Sorts positions on disk instead of in memory. Needed for deep books.
+    pub external: Option<SortOptions>,
+}
+
+impl GenerateOptions<'_> {
+    /// Options for generating the standard book of the given ply
+    pub fn standard(ply: u32) -> Self {
+        GenerateOptions {
+            roots: vec![Position::empty()],
+            ply: PlyTarget::Absolute(ply),
+            out: None,
+            use_book: None,
+            validate: false,
+            annotate: false,
+            shard: None,
+            external: None,
+        }
+    }
+
+    /// The path of the whole book. Shards are saved next to it unless out is given.
+    fn get_book_path(&self) -> Result<PathBuf, Error> {
+        match (self.out, self.ply) {
+            (Some(path), _) => Ok(path.to_path_buf()),
+            (None, PlyTarget::Absolute(ply)) => {
+                create_dir_all(BOOK_FOLDER)?;
+                Ok(get_path_for_ply(ply))
+            }
+            (None, PlyTarget::Relative(_)) => Err(Error::Format(
+                "An output file is needed when the ply is relative".to_string(),
+            )),
+        }
+    }
+}
+
+pub fn generate_book(options: &GenerateOptions) -> Result<(), Error> {
+    let book_path = match (options.shard, options.out) {
+        (Some(shard), None) => shard.get_path(&options.get_book_path()?),
+        _ => options.get_book_path()?,
+    };
+    match &options.external {
+        None => generate_in_memory(options, &book_path),
+        Some(sort_options) => generate_external(options, &book_path, sort_options),
+    }
+}
+
+fn generate_in_memory(options: &GenerateOptions, book_path: &Path) -> Result<(), Error> {
+    let set = find_positions_to_solve(&options.roots, options.ply)?;
+    let position_count = set.len();
+
+    let existing_book = Book::open(book_path).unwrap_or_else(|_| Book::empty());
+    if !existing_book.is_empty() {
+        println!("Found {} existing positions", existing_book.len());
+    }
+
+    let file = File::create(book_path)?;
+    let mut book_writer = BookWriter::create(file, BookFormat::Hex);
+    solve_positions(
+        options,
+        book_path,
+        &mut book_writer,
+        set.into_iter().map(Ok),
+        position_count,
+        |position| Ok(existing_book.get_entry(position)),
+    )?;
+    book_writer.finish()?;
+    Ok(())
+}
+
+/// Like generate_in_memory but positions and existing entries are sorted on disk so that deep books
+/// can be generated with a bounded amount of memory. The book is written to a partial file that is
+/// renamed when the book is complete. An interrupted run continues from both the book and the
+/// partial file.
+fn generate_external(
+    options: &GenerateOptions,
+    book_path: &Path,
+    sort_options: &SortOptions,
+) -> Result<(), Error> {
+    let mut sorter = ExternalSorter::new(sort_options);
+    let mut result = Ok(());
+    explore_roots(&options.roots, options.ply, &mut |position| {
+        if result.is_ok() {
+            result = sorter.push(BookEntry::new(&position, Score::Unknown));
+        }
+    })?;
+    result?;
+    let positions = sorter.finish_to_file()?;
+
+    let partial_path = get_partial_path(book_path);
+    let mut sorter = ExternalSorter::new(sort_options);
+    let mut existing_count = 0;
+    if partial_path.exists() {
+        truncate_torn_line(&partial_path)?;
+    }
+    for path in [book_path, partial_path.as_path()] {
+        if path.exists() {
+            for entry in BookReader::open(path)? {
+                sorter.push(entry?)?;
+                existing_count += 1;
+            }
+        }
+    }
+    if existing_count > 0 {
+        println!("Found {} existing positions", existing_count);
+    }
+    let mut existing = sorter.finish()?.peekable();
+
+    let file = File::create(&partial_path)?;
+    let mut book_writer = BookWriter::create_sorted(file, BookFormat::Hex);
+    let position_iter = positions
+        .entries()?
+        .map(|entry| entry.map(|entry| entry.get_position()));
+    solve_positions(
+        options,
+        book_path,
+        &mut book_writer,
+        position_iter,
+        positions.count,
+        |position| {
+            // positions are asked in ascending order so entries before them are not needed
+            let code = position.to_position_code();
+            let mut found: Option<BookEntry> = None;
+            while let Some(entry) = existing.next_if(|entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |entry| entry.get_position_code() <= code)
+            }) {
+                let entry = entry?;
+                let annotated = found.is_some_and(|found| found.has_annotations());
+                if entry.get_position_code() == code && !annotated {
+                    found = Some(entry);
+                }
+            }
+            Ok(found)
+        },
+    )?;
+    book_writer.finish()?;
+    rename(&partial_path, book_path)?;
+    Ok(())
+}
+
+/// E.g. books/7x6-ply12.txt.partial
+fn get_partial_path(book_path: &Path) -> PathBuf {
+    let mut path = book_path.as_os_str().to_owned();
+    path.push(".partial");
+    PathBuf::from(path)
+}
+
+/// Cuts off the last line of a text file if it has no line break, which is what a run that was
+/// interrupted while writing leaves behind. Lines are then appended after the last complete one.
+fn truncate_torn_line(path: &Path) -> Result<(), Error> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let length = file.metadata()?.len();
+    let mut end = length;
+    let mut buffer = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(buffer.len() as u64);
+        let chunk = &mut buffer[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(i) = chunk.iter().rposition(|byte| *byte == b'\n') {
+            end = start + i as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if end < length {
+        file.set_len(end)?;
+        println!("Removed an incomplete last line from {}", path.display());
+    }
+    Ok(())
+}
+
+/// Solves the positions in ascending order, or only the ones in the shard if there is one, and
+/// writes them to the book. Entries that find_existing returns are written without solving.
+fn solve_positions<W, I, F>(
+    options: &GenerateOptions,
+    book_path: &Path,
+    book_writer: &mut BookWriter<W>,
+    positions: I,
+    position_count: usize,
+    mut find_existing: F,
+) -> Result<(), Error>
+where
+    W: Write,
+    I: Iterator<Item = Result<Position, Error>>,
+    F: FnMut(&Position) -> Result<Option<BookEntry>, Error>,
+{
+    let (use_book, validate, annotate) = (options.use_book, options.validate, options.annotate);
+    let total_count = match options.shard {
+        Some(shard) => shard.get_position_count(position_count),
+        None => position_count,
+    };
+    println!(
+        "There are {} positions to solve. Saving book as {}",
+        total_count,
+        book_path.display()
+    );
+
+    let mut total_benchmark = Benchmark::empty();
+    let mut solved = 0;
+
+    let mut engine = Engine::new();
+    if let Some(another_book_path) = use_book {
+        let another_book = Box::new(Book::open_validated(another_book_path, validate)?);
+        another_book.build_lookup_index();
+        engine.set_book(another_book);
+    }
+
+    let positions = positions
+        .enumerate()
+        .filter(|(index, _)| options.shard.is_none_or(|shard| shard.contains(*index)))
+        .map(|(_, position)| position);
+    for (count, pos) in positions.enumerate() {
+        let pos = pos?;
+        if let Some(existing_entry) = find_existing(&pos)? {
+            if !annotate || existing_entry.get_best_move().is_some() {
+                book_writer.write_entry(&existing_entry)?;
+                continue;
+            }
+        }
+
+        let benchmark = if annotate {
+            let (entry, benchmark) = solve_annotated(&mut engine, &pos);
+            book_writer.write_entry(&entry)?;
+            benchmark
+        } else {
+            engine.set_position(pos);
+            let benchmark = Benchmark::run(&mut engine);
+            book_writer.write_entry(&BookEntry::new(&pos, benchmark.score))?;
+            benchmark
+        };
+
+        total_benchmark = total_benchmark.add(&benchmark);
+        solved += 1;
 
         if count % 20 == 0 {
             let average_work = total_benchmark.work_count as f64 / solved as f64;
@@ -452,24 +2270,436 @@ pub fn generate_book(ply: u32, use_book: Option<&Path>) -> Result<(), std::io::E
     Ok(())
 }
 
-pub fn verify_book(book1_path: &Path, book2_path: &Path) -> Result<(), std::io::Error> {
-    let book1 = Book::open(book1_path)?;
-    let book2 = Book::open(book2_path)?;
+/// Combines the books generated by the shards of generate_book. Fails without writing anything if
+/// a position is missing, if the shards have a position that should not be there or if the shards
+/// have different scores for the same position. A position that is in more than one shard with the
+/// same score is written only once but reported because it means that the shards overlap.
+pub fn merge_shards(options: &GenerateOptions, shard_paths: &[&Path]) -> Result<(), Error> {
+    let book_path = options.get_book_path()?;
+    let expected = find_positions_to_solve(&options.roots, options.ply)?;
+    let mut entries = vec![];
+    for path in shard_paths {
+        let shard = Book::open_validated(path, options.validate)?;
+        println!("Found {} positions in {}", shard.len(), path.display());
+        entries.extend(shard.iter());
+    }
+    let merged = combine_shard_entries(&expected, entries)?;
+
+    let file = LineWriter::new(File::create(&book_path)?);
+    let mut book_writer = BookWriter::create_sorted(file, BookFormat::Hex);
+    for entry in &merged {
+        book_writer.write_entry(entry)?;
+    }
+    book_writer.finish()?;
+    println!(
+        "Saved {} positions to {}",
+        merged.len(),
+        book_path.display()
+    );
+    Ok(())
+}
+
+/// Sorts the entries of all shards and checks that they are exactly the expected positions, each in
+/// only one shard. Shards never overlap, so a duplicate means that a wrong shard file was given.
+fn combine_shard_entries(
+    expected: &BTreeSet<Position>,
+    mut entries: Vec<BookEntry>,
+) -> Result<Vec<BookEntry>, Error> {
+    let expected_codes: HashSet<BoardInteger> = expected
+        .iter()
+        .map(|position| position.to_position_code())
+        .collect();
+    entries.sort();
+
+    let mut merged: Vec<BookEntry> = Vec::with_capacity(entries.len());
+    let mut duplicate_count = 0;
+    for entry in entries {
+        match merged.last() {
+            Some(previous) if previous.get_position_code() == entry.get_position_code() => {
+                duplicate_count += 1;
+                if previous.get_score() != entry.get_score() {
+                    println!(
+                        "Conflicting scores for {}",
+                        entry.get_position().as_hex_string()
+                    );
+                }
+            }
+            _ => merged.push(entry),
+        }
+    }
+
+    let unexpected: Vec<&BookEntry> = merged
+        .iter()
+        .filter(|entry| !expected_codes.contains(&entry.get_position_code()))
+        .collect();
+    for entry in &unexpected {
+        println!(
+            "Unexpected position {}",
+            entry.get_position().as_hex_string()
+        );
+    }
+    let merged_codes: HashSet<BoardInteger> = merged
+        .iter()
+        .map(|entry| entry.get_position_code())
+        .collect();
+    let missing: Vec<&Position> = expected
+        .iter()
+        .filter(|position| !merged_codes.contains(&position.to_position_code()))
+        .collect();
+    for position in &missing {
+        println!("Missing position {}", position.as_hex_string());
+    }
+
+    if duplicate_count > 0 || !missing.is_empty() || !unexpected.is_empty() {
+        return Err(Error::ShardMismatch {
+            duplicate_count,
+            missing_count: missing.len(),
+            unexpected_count: unexpected.len(),
+        });
+    }
+    Ok(merged)
+}
+
+/// Computes scores by minimaxing the scores of a book with positions of a higher ply
+struct Backup<'a> {
+    book: &'a Book,
+    /// The ply of the positions that are looked up from the book
+    source_ply: u32,
+    /// Scores of positions between the target ply and the source ply by normalized position code
+    scores: HashMap<BoardInteger, Score>,
+    /// Positions of the source ply that the book does not have
+    missing: BTreeSet<Position>,
+}
+
+impl Backup<'_> {
+    fn new(book: &Book, source_ply: u32) -> Backup<'_> {
+        Backup {
+            book,
+            source_ply,
+            scores: HashMap::new(),
+            missing: BTreeSet::new(),
+        }
+    }
+
+    fn score(&mut self, position: &Position) -> Score {
+        if position.get_ply() == self.source_ply {
+            let score = self.book.get(position);
+            if score == Score::Unknown {
+                self.missing.insert(position.normalize());
+            }
+            return score;
+        }
+
+        let (code, symmetric) = position.to_normalized_position_code();
+        if let Some(score) = self.scores.get(&code) {
+            return *score;
+        }
+        let score = self.score_children(position, symmetric);
+        self.scores.insert(code, score);
+        score
+    }
+
+    fn score_children(&mut self, position: &Position, symmetric: bool) -> Score {
+        if position.get_immediate_wins().0 != 0 {
+            return Score::Win;
+        }
+        if position.get_ply() == BOARD_WIDTH * BOARD_HEIGHT {
+            return Score::Draw;
+        }
+
+        // Moves under enemy threats lose immediately, so books do not have the positions after
+        // them. A mirror image has the same score so only half of the moves in a symmetric position
+        // are needed.
+        let mut move_bitmap = position.get_unblocked_moves();
+        if symmetric {
+            move_bitmap = move_bitmap.get_left_half();
+        }
+
+        // the best score is somewhere between these bounds
+        let mut lower = Score::Loss;
+        let mut upper = Score::Loss;
+        for x in 0..BOARD_WIDTH {
+            if !move_bitmap.has_move(x) {
+                continue;
+            }
+            let child = position.position_after_drop(x).unwrap();
+            let score = self.score(&child).flip();
+            let (child_lower, child_upper) = match score {
+                Score::Unknown => (Score::Loss, Score::Win),
+                Score::DrawOrLoss => (Score::Loss, Score::Draw),
+                Score::DrawOrWin => (Score::Draw, Score::Win),
+                exact => (exact, exact),
+            };
+            lower = if child_lower > lower {
+                child_lower
+            } else {
+                lower
+            };
+            upper = if child_upper > upper {
+                child_upper
+            } else {
+                upper
+            };
+        }
+
+        match (lower, upper) {
+            (lower, upper) if lower == upper => lower,
+            (Score::Loss, Score::Draw) => Score::DrawOrLoss,
+            (Score::Draw, Score::Win) => Score::DrawOrWin,
+            _ => Score::Unknown,
+        }
+    }
+}
+
+/// Creates a book of a lower ply from a book of a higher ply without searching. The source book
+/// should have every position of its ply that generate_book would solve. Otherwise some scores
+/// become bounds or unknown and the missing positions are reported.
+pub fn derive_book(
+    source_path: &Path,
+    ply: u32,
+    out_path: &Path,
+    format: BookFormat,
+    validate: bool,
+) -> Result<(), Error> {
+    let book = Book::open_validated(source_path, validate)?;
+    let source_ply = (ply + 1..=BOARD_WIDTH * BOARD_HEIGHT)
+        .find(|source_ply| book.contains_ply(*source_ply))
+        .ok_or_else(|| {
+            Error::Format(format!(
+                "{} has no positions with more than {} plies",
+                source_path.display(),
+                ply
+            ))
+        })?;
+    println!(
+        "Deriving ply {} from the ply {} positions of {}",
+        ply,
+        source_ply,
+        source_path.display()
+    );
+
+    let mut backup = Backup::new(&book, source_ply);
+    let positions = find_positions_to_solve(&[Position::empty()], PlyTarget::Absolute(ply))?;
+    let mut entries: Vec<BookEntry> = positions
+        .iter()
+        .map(|position| BookEntry::new(position, backup.score(position)))
+        .filter(|entry| entry.get_score() != Score::Unknown)
+        .collect();
+    entries.sort();
+    let unknown_count = positions.len() - entries.len();
+
+    let file = LineWriter::new(File::create(out_path)?);
+    let mut book_writer = BookWriter::create_sorted(file, format);
+    for entry in &entries {
+        book_writer.write_entry(entry)?;
+    }
+    book_writer.finish()?;
+    println!(
+        "Saved {} positions to {}",
+        entries.len(),
+        out_path.display()
+    );
+    if !backup.missing.is_empty() {
+        println!(
+            "{} positions of ply {} are missing from the source book so {} positions could not be \
+             scored and others may have bounds instead of exact scores:",
+            backup.missing.len(),
+            source_ply,
+            unknown_count
+        );
+        for position in &backup.missing {
+            println!("{}", position.as_hex_string());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the entries whose scores contradict the scores minimaxed from the next higher ply in the
+/// same book, together with the minimaxed scores, and the number of positions that are missing
+/// from the higher plies. Missing positions make minimaxed scores bounds or unknown, which are
+/// compatible with more scores, so they cannot cause false inconsistencies.
+fn find_inconsistencies(book: &Book) -> (Vec<(BookEntry, Score)>, usize) {
+    let plies: Vec<u32> = (0..=BOARD_WIDTH * BOARD_HEIGHT)
+        .filter(|ply| book.contains_ply(*ply))
+        .collect();
+    let mut inconsistencies = vec![];
+    let mut missing_count = 0;
+    for pair in plies.windows(2) {
+        let (ply, source_ply) = (pair[0], pair[1]);
+        let mut backup = Backup::new(book, source_ply);
+        for entry in book.iter() {
+            let position = entry.get_position();
+            if position.get_ply() != ply {
+                continue;
+            }
+            let score = backup.score(&position);
+            if !entry.get_score().is_compatible(score) {
+                inconsistencies.push((entry, score));
+            }
+        }
+        missing_count += backup.missing.len();
+    }
+    (inconsistencies, missing_count)
+}
+
+/// Checks that the scores of the books agree with each other between the plies that they have
+pub fn check_book_consistency(book_paths: &[&Path], validate: bool) -> Result<(), Error> {
+    let mut book = Book::empty();
+    for path in book_paths {
+        book = book.merge(&Book::open_validated(path, validate)?, ConflictPolicy::Fail)?;
+    }
+
+    let (inconsistencies, missing_count) = find_inconsistencies(&book);
+    for (entry, score) in &inconsistencies {
+        println!(
+            "{} stored: {:?} minimaxed: {:?}",
+            entry.get_position().as_hex_string(),
+            entry.get_score(),
+            score
+        );
+    }
+    if missing_count > 0 {
+        println!(
+            "{} positions are missing from higher plies so some scores could only be checked \
+             against bounds",
+            missing_count
+        );
+    }
+
+    if inconsistencies.is_empty() {
+        println!("All {} positions are consistent", book.len());
+        Ok(())
+    } else {
+        Err(Error::Inconsistent {
+            count: inconsistencies.len(),
+        })
+    }
+}
+
+/// Reads a book of any size in ascending order of position codes. The format is detected if it is
+/// not given.
+pub fn read_sorted(
+    book_path: &Path,
+    format: Option<BookFormat>,
+    validate: bool,
+    sort_options: &SortOptions,
+) -> Result<SortedEntries, Error> {
+    let reader = match format {
+        Some(format) => BookReader::open_with_format(book_path, format)?,
+        None => BookReader::open(book_path)?,
+    };
+    let mut sorter = ExternalSorter::new(sort_options);
+    for entry in reader {
+        let entry = entry?;
+        if validate {
+            entry.validate()?;
+        }
+        sorter.push(entry)?;
+    }
+    sorter.finish()
+}
+
+/// Like BookDiff but only counts the entries so that books larger than memory can be compared
+#[derive(Default)]
+struct DiffCounts {
+    conflict_count: usize,
+    only_in_first_count: usize,
+    only_in_second_count: usize,
+    shared_count: usize,
+}
 
-    let positions1 = book1.to_position_set();
-    let positions2 = book2.to_position_set();
+/// Entries with an unknown score are skipped as if the books did not have them, so they are never
+/// counted as conflicts
+fn count_differences<I1, I2>(entries1: I1, entries2: I2) -> Result<DiffCounts, Error>
+where
+    I1: Iterator<Item = Result<BookEntry, Error>>,
+    I2: Iterator<Item = Result<BookEntry, Error>>,
+{
+    let is_known = |entry: &Result<BookEntry, Error>| {
+        entry
+            .as_ref()
+            .map_or(true, |entry| entry.get_score() != Score::Unknown)
+    };
+    let mut counts = DiffCounts::default();
+    let mut iter1 = entries1.filter(is_known).peekable();
+    let mut iter2 = entries2.filter(is_known).peekable();
+    let mut next1 = next_position_group(&mut iter1)?;
+    let mut next2 = next_position_group(&mut iter2)?;
+    loop {
+        let (ordering, group) = match (&next1, &next2) {
+            (Some(group1), Some(group2)) => match group1[0].cmp(&group2[0]) {
+                Ordering::Equal => (Ordering::Equal, [&group1[..], &group2[..]].concat()),
+                Ordering::Less => (Ordering::Less, group1.clone()),
+                Ordering::Greater => (Ordering::Greater, group2.clone()),
+            },
+            (Some(group1), None) => (Ordering::Less, group1.clone()),
+            (None, Some(group2)) => (Ordering::Greater, group2.clone()),
+            (None, None) => break,
+        };
+        match ordering {
+            Ordering::Less => counts.only_in_first_count += 1,
+            Ordering::Greater => counts.only_in_second_count += 1,
+            Ordering::Equal => counts.shared_count += 1,
+        }
+        // the same book may also have different scores for a position
+        if group
+            .iter()
+            .any(|entry| entry.get_score() != group[0].get_score())
+        {
+            counts.conflict_count += 1;
+        }
+        if ordering != Ordering::Greater {
+            next1 = next_position_group(&mut iter1)?;
+        }
+        if ordering != Ordering::Less {
+            next2 = next_position_group(&mut iter2)?;
+        }
+    }
+    Ok(counts)
+}
 
-    let shared = positions1.intersection(&positions2);
-    let conflict_count = shared
-        .filter(|pos| book1.get(pos) != book2.get(pos))
-        .count();
+/// Takes the next entry of sorted entries together with the entries after it that have the same
+/// position but a different score or annotations
+fn next_position_group<I>(entries: &mut Peekable<I>) -> Result<Option<Vec<BookEntry>>, Error>
+where
+    I: Iterator<Item = Result<BookEntry, Error>>,
+{
+    let Some(first) = entries.next().transpose()? else {
+        return Ok(None);
+    };
+    let mut group = vec![first];
+    let same_position = |entry: &Result<BookEntry, Error>| {
+        entry
+            .as_ref()
+            .is_ok_and(|entry| entry.get_position_code() == first.get_position_code())
+    };
+    while let Some(entry) = entries.next_if(same_position) {
+        group.push(entry?);
+    }
+    Ok(Some(group))
+}
 
-    if conflict_count > 0 {
-        panic!("{} positions with conflicting scores", conflict_count);
+/// Compares books by streaming them in sorted order, so the books may be larger than memory. The
+/// formats of the books are detected unless they are given.
+pub fn verify_book(
+    book1_path: &Path,
+    book2_path: &Path,
+    formats: [Option<BookFormat>; 2],
+    validate: bool,
+    sort_options: &SortOptions,
+) -> Result<(), Error> {
+    let entries1 = read_sorted(book1_path, formats[0], validate, sort_options)?;
+    let entries2 = read_sorted(book2_path, formats[1], validate, sort_options)?;
+
+    let diff = count_differences(entries1, entries2)?;
+    if diff.conflict_count > 0 {
+        return Err(Error::Conflict {
+            count: diff.conflict_count,
+        });
     }
 
-    let count1 = positions1.len();
-    let count2 = positions2.len();
+    let count1 = diff.only_in_first_count + diff.shared_count;
+    let count2 = diff.only_in_second_count + diff.shared_count;
     let width = cmp::max(count1.to_string().len(), count2.to_string().len());
     println!(
         "There are {:>width$} positions in {}",
@@ -485,27 +2715,49 @@ pub fn verify_book(book1_path: &Path, book2_path: &Path) -> Result<(), std::io::
     );
     println!();
 
-    let diff_count = positions1.symmetric_difference(&positions2).count();
-    if diff_count == 0 {
+    if diff.only_in_first_count == 0 && diff.only_in_second_count == 0 {
         println!("The books match exactly");
     } else {
-        let shared_count = positions1.intersection(&positions2).count();
         println!(
             "The books have matching scores but they share only {} positions",
-            shared_count
+            diff.shared_count
         );
     }
 
     Ok(())
 }
 
-fn find_positions_to_solve(ply: u32) -> BTreeSet<Position> {
+fn find_positions_to_solve(
+    roots: &[Position],
+    ply: PlyTarget,
+) -> Result<BTreeSet<Position>, Error> {
     let mut set = BTreeSet::new();
-    explore_tree(Position::empty(), ply, &mut |pos| {
-        let pos = pos.normalize();
-        set.insert(pos);
-    });
-    set
+    explore_roots(roots, ply, &mut |pos| {
+        set.insert(pos.normalize());
+    })?;
+    Ok(set)
+}
+
+/// Calls the function for every position of the ply that can be reached from the roots. The same
+/// position may be found more than once.
+fn explore_roots<F>(roots: &[Position], ply: PlyTarget, f: &mut F) -> Result<(), Error>
+where
+    F: FnMut(Position),
+{
+    for root in roots {
+        let depth = match ply {
+            PlyTarget::Absolute(ply) => ply.checked_sub(root.get_ply()).ok_or_else(|| {
+                Error::Format(format!(
+                    "Position {} already has more than {} plies",
+                    root.as_hex_string(),
+                    ply
+                ))
+            })?,
+            PlyTarget::Relative(depth) => depth,
+        };
+        explore_tree(*root, depth, f);
+    }
+    Ok(())
 }
 
 /// Explores the game tree up to a certain depth and calls the function for each leaf node. This
@@ -538,3 +2790,1193 @@ where
         explore_tree(*m, max_depth - 1, f);
     }
 }
+
+/// Options for audit_book
+pub struct AuditOptions<'a> {
+    /// Audits only this many randomly chosen entries instead of all of them
+    pub sample_size: Option<usize>,
+    /// The same seed always chooses the same sample from the same book
+    pub seed: u64,
+    pub thread_count: usize,
+    /// A hex book where the engine's scores are appended as soon as they are known. Positions that
+    /// it already has are not solved again so an interrupted audit can continue where it stopped.
+    pub progress_path: Option<&'a Path>,
+}
+
+/// SplitMix64, which is good enough for choosing samples and needs no dependencies
+pub(crate) struct Random(pub(crate) u64);
+
+impl Random {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Chooses sample_size entries with a partial Fisher-Yates shuffle and returns them in ascending
+/// order of position codes
+fn sample_entries(mut entries: Vec<BookEntry>, sample_size: usize, seed: u64) -> Vec<BookEntry> {
+    let mut random = Random(seed);
+    let sample_size = cmp::min(sample_size, entries.len());
+    for i in 0..sample_size {
+        let j = i + (random.next() % (entries.len() - i) as u64) as usize;
+        entries.swap(i, j);
+    }
+    entries.truncate(sample_size);
+    entries.sort();
+    entries
+}
+
+/// Solves the positions of the entries with engines that do not use a book, one engine per thread.
+/// on_solved is called with each entry and the engine's score as soon as the score is known.
+fn solve_without_book<F>(
+    entries: &[BookEntry],
+    thread_count: usize,
+    on_solved: F,
+) -> Result<(), Error>
+where
+    F: Fn(BookEntry, Score) -> Result<(), Error> + Sync,
+{
+    let next_index = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..thread_count.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut engine = Engine::new();
+                    loop {
+                        let index = next_index.fetch_add(1, AtomicOrdering::Relaxed);
+                        let Some(&entry) = entries.get(index) else {
+                            return Ok(());
+                        };
+                        engine.set_position(entry.get_position());
+                        on_solved(entry, engine.solve())?;
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("Audit thread panicked"))
+    })
+}
+
+/// Returns the entries whose scores are not compatible with the engine's scores together with the
+/// engine's scores
+fn find_mismatches(
+    entries: &[BookEntry],
+    engine_scores: &HashMap<BoardInteger, Score>,
+) -> Vec<(BookEntry, Score)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let engine_score = *engine_scores.get(&entry.get_position_code())?;
+            if entry.get_score().is_compatible(engine_score) {
+                None
+            } else {
+                Some((*entry, engine_score))
+            }
+        })
+        .collect()
+}
+
+/// Re-solves the positions of a book without using any book and reports the positions whose scores
+/// are wrong
+pub fn audit_book(book_path: &Path, options: &AuditOptions, validate: bool) -> Result<(), Error> {
+    let book = Book::open_validated(book_path, validate)?;
+    let mut entries: Vec<BookEntry> = book.iter().collect();
+    if let Some(sample_size) = options.sample_size {
+        entries = sample_entries(entries, sample_size, options.seed);
+    }
+
+    let mut engine_scores = HashMap::new();
+    let mut progress_writer = None;
+    if let Some(progress_path) = options.progress_path {
+        if progress_path.exists() {
+            truncate_torn_line(progress_path)?;
+            let progress = Book::open_with_format(progress_path, BookFormat::Hex)?;
+            for entry in progress.iter() {
+                engine_scores.insert(entry.get_position_code(), entry.get_score());
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(progress_path)?;
+        progress_writer = Some(BookWriter::create(LineWriter::new(file), BookFormat::Hex));
+    }
+
+    let unsolved: Vec<BookEntry> = entries
+        .iter()
+        .filter(|entry| !engine_scores.contains_key(&entry.get_position_code()))
+        .copied()
+        .collect();
+    println!(
+        "Auditing {} positions of which {} were already solved",
+        entries.len(),
+        entries.len() - unsolved.len()
+    );
+
+    let state = Mutex::new((progress_writer, engine_scores, 0));
+    solve_without_book(&unsolved, options.thread_count, |entry, score| {
+        let mut state = state.lock().unwrap();
+        let (progress_writer, engine_scores, solved_count) = &mut *state;
+        if let Some(writer) = progress_writer {
+            writer.write_entry(&BookEntry::new(&entry.get_position(), score))?;
+        }
+        engine_scores.insert(entry.get_position_code(), score);
+        *solved_count += 1;
+        if *solved_count % 100 == 0 {
+            println!("Solved {} out of {}", solved_count, unsolved.len());
+        }
+        Ok(())
+    })?;
+
+    let (progress_writer, engine_scores, _) = state.into_inner().unwrap();
+    if let Some(writer) = progress_writer {
+        writer.finish()?;
+    }
+
+    let mismatches = find_mismatches(&entries, &engine_scores);
+    for (entry, engine_score) in &mismatches {
+        println!(
+            "{} book: {:?} engine: {:?}",
+            entry.get_position().as_hex_string(),
+            entry.get_score(),
+            engine_score
+        );
+    }
+
+    if mismatches.is_empty() {
+        println!("All {} positions have correct scores", entries.len());
+        Ok(())
+    } else {
+        Err(Error::AuditMismatch {
+            count: mismatches.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::{env, fs, process};
+
+    fn sample_book() -> Book {
+        let mut str = String::new();
+        str.push_str("0000040812A04081+\n"); // variation 4444
+        str.push_str("000004081040C103-\n"); // variation 1234
+        Book::from_lines(&str).unwrap()
+    }
+
+    /// Solves the positions that are the given numbers of plies after the root. The root should be
+    /// far enough into the game that the engine solves the positions quickly without a book.
+    fn solved_book(root: &str, depths: &[u32]) -> Book {
+        let root = Position::from_variation(root).unwrap();
+        let mut engine = Engine::new();
+        let mut book = Book::empty();
+        for depth in depths {
+            for position in find_positions_to_solve(&[root], PlyTarget::Relative(*depth)).unwrap() {
+                engine.set_position(position);
+                book.add_entry(BookEntry::new(&position, engine.solve()));
+            }
+        }
+        book.sort_and_shrink();
+        book
+    }
+
+    fn write_book(book: &Book, format: BookFormat) -> Vec<u8> {
+        let mut book_writer = BookWriter::create(vec![], format);
+        for entry in book.iter() {
+            book_writer.write_entry(&entry).unwrap();
+        }
+        book_writer.finish().unwrap()
+    }
+
+    #[test]
+    fn versioned_round_trip() {
+        let book = sample_book();
+        let bytes = write_book(&book, BookFormat::Versioned);
+        assert!(bytes.starts_with(&VERSIONED_MAGIC));
+
+        let read_book = Book::read_versioned_book(&mut BufReader::new(&bytes[..])).unwrap();
+        assert_eq!(read_book.len(), 2);
+        let position = Position::from_variation("4444").unwrap();
+        assert_eq!(read_book.get(&position), Score::Win);
+    }
+
+    #[test]
+    fn annotations() {
+        // 7654 is normalized to its mirror image 1234
+        let position = Position::from_variation("7654").unwrap();
+        let entry = BookEntry::annotated(&position, Score::Loss, Some(1));
+        assert_eq!(entry.get_score(), Score::Loss);
+        assert_eq!(entry.get_best_move(), Some(5));
+        assert_eq!(entry.get_position(), position.normalize());
+
+        let line = entry.to_hex_string();
+        assert_eq!(line, "000004081040C103- 6");
+        assert!(BookEntry::from_hex_string(&line) == Some(entry));
+        assert!(BookEntry::from_hex_string("000004081040C103- 8").is_none());
+        assert!(BookEntry::from_hex_string("000004081040C103- 6 30").is_none());
+
+        let mut book = Book::empty();
+        book.add_entry(entry);
+        assert_eq!(book.best_move(&position), Some(1));
+        assert_eq!(book.best_move(&position.flip()), Some(5));
+
+        for format in [BookFormat::Versioned, BookFormat::Compressed] {
+            let bytes = write_book(&book, format);
+            let read_book = Book::from_bytes(&bytes).unwrap();
+            assert!(read_book.get_entry(&position) == Some(entry));
+        }
+    }
+
+    #[test]
+    fn solve_annotated_finds_a_best_move() {
+        let mut engine = Engine::new();
+        for variation in ["4444443", "44444433", "444444332"] {
+            let position = Position::from_variation(variation).unwrap();
+            let (entry, _) = solve_annotated(&mut engine, &position);
+            engine.set_position(position);
+            assert_eq!(entry.get_score(), engine.solve());
+            let x = entry.get_best_move().unwrap();
+            engine.set_position(entry.get_position().position_after_drop(x).unwrap());
+            assert_eq!(entry.get_score(), engine.solve().flip());
+        }
+    }
+
+    #[test]
+    fn probe_moves() {
+        let book = sample_book();
+        // moving to 1234 wins because the book has it as a loss for the player to move
+        let position = Position::from_variation("123").unwrap();
+        let moves = book.probe_moves(&position);
+        assert_eq!(moves.len(), BOARD_WIDTH as usize);
+        assert!(moves.contains(&(3, Score::Win)));
+        assert!(moves.contains(&(0, Score::Unknown)));
+
+        // 7654 is found through its mirror image
+        let position = Position::from_variation("765").unwrap();
+        assert!(book.probe_moves(&position).contains(&(3, Score::Win)));
+
+        let position = Position::from_variation("111111").unwrap();
+        assert!(!book.probe_moves(&position).iter().any(|(x, _)| *x == 0));
+    }
+
+    #[test]
+    fn contains_ply() {
+        let mut book = Book::empty();
+        let position = Position::from_variation("123456712345").unwrap();
+        book.add_entry(BookEntry::new(&position, Score::Draw));
+        assert!(book.contains_ply(12));
+        for ply in [0, 4, 8, 13] {
+            assert!(!book.contains_ply(ply));
+        }
+
+        let bytes = write_book(&book, BookFormat::Versioned);
+        let read_book = Book::from_bytes(&bytes).unwrap();
+        assert!(read_book.contains_ply(12));
+        assert!(!read_book.contains_ply(4));
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let book = sample_book();
+        let bytes = write_book(&book, BookFormat::Compressed);
+        assert!(bytes.len() < write_book(&book, BookFormat::Versioned).len());
+
+        let read_book = Book::from_bytes(&bytes).unwrap();
+        assert_eq!(read_book.len(), 2);
+        assert_eq!(
+            read_book.get(&Position::from_variation("4444").unwrap()),
+            Score::Win
+        );
+        assert_eq!(
+            read_book.get(&Position::from_variation("1234").unwrap()),
+            Score::Loss
+        );
+        assert!(read_book.contains_ply(4));
+
+        let mut truncated = bytes.clone();
+        truncated.remove(VersionedHeader::SIZE);
+        assert!(Book::from_bytes(&truncated).is_err());
+    }
+
+    #[test]
+    fn sorted_versioned_book_is_searched_in_place() {
+        let mut book_writer = BookWriter::create_sorted(vec![], BookFormat::Versioned);
+        for entry in sample_book().iter() {
+            book_writer.write_entry(&entry).unwrap();
+        }
+        let bytes = book_writer.finish().unwrap();
+
+        let book = Book::read_versioned_book(&mut BufReader::new(&bytes[..])).unwrap();
+        assert!(matches!(&book.entries, Entries::Raw(words) if words.len() == 2));
+        let position = Position::from_variation("1234").unwrap();
+        assert_eq!(book.get(&position), Score::Loss);
+        assert_eq!(book.get(&Position::empty()), Score::Unknown);
+        assert!(book.contains_ply(4));
+    }
+
+    #[test]
+    fn sorted_writer_rejects_unordered_entries() {
+        let mut book_writer = BookWriter::create_sorted(vec![], BookFormat::Hex);
+        let entries: Vec<BookEntry> = sample_book().iter().collect();
+        book_writer.write_entry(&entries[1]).unwrap();
+        assert!(book_writer.write_entry(&entries[0]).is_err());
+    }
+
+    #[test]
+    fn include_book_merges_sorted_entries() {
+        let mut book = Book::from_lines("000004081040C103-\n").unwrap();
+        book.include_book(&Book::from_lines("0000040812A04081+\n").unwrap());
+        let codes: Vec<BoardInteger> = book.iter().map(|e| e.get_position_code()).collect();
+        assert_eq!(codes.len(), 2);
+        assert!(codes[0] < codes[1]);
+    }
+
+    #[test]
+    fn diff() {
+        let book1 = Book::from_lines("0000040812A04081+\n000004081040C103-\n").unwrap();
+        let book2 = Book::from_lines("0000040812A04081=\n0000040820E08081+\n").unwrap();
+        let diff = book1.diff(&book2);
+        assert_eq!(diff.shared_count, 1);
+        assert_eq!(diff.conflicts.len(), 1);
+        assert_eq!(diff.conflicts[0].0.get_score(), Score::Win);
+        assert_eq!(diff.conflicts[0].1.get_score(), Score::Draw);
+        assert_eq!(diff.only_in_first.len(), 1);
+        assert_eq!(diff.only_in_second.len(), 1);
+    }
+
+    #[test]
+    fn merge_policies() {
+        let position = Position::from_variation("4444").unwrap();
+        let reference = Book::from_lines("0000040812A04081>\n000004081040C103-\n").unwrap();
+        let exact = Book::from_lines("0000040812A04081+\n").unwrap();
+        let conflicting = Book::from_lines("0000040812A04081<\n").unwrap();
+
+        let merged = reference
+            .merge(&exact, ConflictPolicy::PreferExact)
+            .unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged.get(&position), Score::Win);
+
+        let merged = reference
+            .merge(&conflicting, ConflictPolicy::PreferExact)
+            .unwrap();
+        assert_eq!(merged.get(&position), Score::Draw);
+
+        let merged = reference
+            .merge(&exact, ConflictPolicy::KeepReference)
+            .unwrap();
+        assert_eq!(merged.get(&position), Score::DrawOrWin);
+
+        let result = reference.merge(&exact, ConflictPolicy::Fail);
+        assert!(matches!(result, Err(Error::Conflict { count: 1 })));
+
+        let result = exact.merge(&conflicting, ConflictPolicy::PreferExact);
+        assert!(matches!(result, Err(Error::Conflict { count: 1 })));
+    }
+
+    #[test]
+    fn sample_entries_is_deterministic() {
+        let entries: Vec<BookEntry> = test_book(4).iter().collect();
+        let sample1 = sample_entries(entries.clone(), 10, 1);
+        let sample2 = sample_entries(entries.clone(), 10, 1);
+        let sample3 = sample_entries(entries.clone(), 10, 2);
+        assert!(sample1 == sample2);
+        assert!(sample1 != sample3);
+        assert!(sample1.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(
+            sample_entries(entries.clone(), 1000, 1).len(),
+            entries.len()
+        );
+    }
+
+    #[test]
+    fn audit_finds_wrong_scores() {
+        let entry = |variation: &str, score: Score| {
+            BookEntry::new(&Position::from_variation(variation).unwrap(), score)
+        };
+        let correct = entry("444444333333222222", Score::Win);
+        let bound = entry("44444433333322222", Score::DrawOrLoss);
+        let wrong = entry("444444555555", Score::Draw);
+        let entries = vec![correct, bound, wrong];
+
+        let engine_scores = Mutex::new(HashMap::new());
+        solve_without_book(&entries, 2, |entry, score| {
+            engine_scores
+                .lock()
+                .unwrap()
+                .insert(entry.get_position_code(), score);
+            Ok(())
+        })
+        .unwrap();
+
+        let mismatches = find_mismatches(&entries, &engine_scores.into_inner().unwrap());
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].0 == wrong);
+        assert_eq!(mismatches[0].1, Score::Win);
+    }
+
+    /// A path in the temporary directory that is unique to the test
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("fourengine-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn audit_resumes_after_a_torn_line() {
+        let book = solved_book("44444433", &[1]);
+        let book_path = temp_path("audit-book.txt");
+        let progress_path = temp_path("audit-progress.txt");
+        fs::write(&book_path, write_book(&book, BookFormat::Hex)).unwrap();
+        let first = book.iter().next().unwrap();
+        let torn = format!("{}\n{}", first.to_hex_string(), &first.to_hex_string()[..8]);
+        fs::write(&progress_path, torn).unwrap();
+
+        let options = AuditOptions {
+            sample_size: None,
+            seed: 0,
+            thread_count: 1,
+            progress_path: Some(&progress_path),
+        };
+        let result = audit_book(&book_path, &options, false);
+        let progress = Book::open_with_format(&progress_path, BookFormat::Hex);
+        fs::remove_file(&book_path).unwrap();
+        fs::remove_file(&progress_path).unwrap();
+        result.unwrap();
+        assert!(progress.unwrap().iter().eq(book.iter()));
+    }
+
+    #[test]
+    fn positions_to_solve_from_roots() {
+        let empty = Position::empty();
+        let standard = find_positions_to_solve(&[empty], PlyTarget::Absolute(4)).unwrap();
+        // nobody can win in 4 plies so every variation of 4 moves is a position to solve
+        let mut every_variation = BTreeSet::new();
+        for i in 0..BOARD_WIDTH.pow(4) {
+            let variation: String = (0..4)
+                .map(|digit| i / BOARD_WIDTH.pow(digit) % BOARD_WIDTH + 1)
+                .map(|column| std::char::from_digit(column, 10).unwrap())
+                .collect();
+            every_variation.insert(Position::from_variation(&variation).unwrap().normalize());
+        }
+        assert!(standard == every_variation);
+
+        let root = Position::from_variation("4453").unwrap();
+        let absolute = find_positions_to_solve(&[root], PlyTarget::Absolute(6)).unwrap();
+        let relative = find_positions_to_solve(&[root], PlyTarget::Relative(2)).unwrap();
+        assert!(absolute == relative);
+        assert!(absolute.iter().all(|pos| pos.get_ply() == 6));
+        assert!(absolute.len() <= 49);
+
+        let result = find_positions_to_solve(&[root], PlyTarget::Absolute(3));
+        assert!(matches!(result, Err(Error::Format(_))));
+
+        let roots = [root, Position::from_variation("4444").unwrap()];
+        let positions = find_positions_to_solve(&roots, PlyTarget::Relative(0)).unwrap();
+        assert_eq!(positions.len(), 2);
+    }
+
+    #[test]
+    fn parse_shard() {
+        assert_eq!(
+            "2/4".parse::<Shard>().unwrap(),
+            Shard { index: 2, count: 4 }
+        );
+        for invalid in ["0/4", "5/4", "1", "a/4", "1/0"] {
+            assert!(matches!(invalid.parse::<Shard>(), Err(Error::Format(_))));
+        }
+        let path = Shard { index: 1, count: 4 }.get_path(Path::new("books/7x6-ply8.txt"));
+        assert_eq!(path, Path::new("books/7x6-ply8-shard1of4.txt"));
+    }
+
+    #[test]
+    fn shards_combine_to_all_positions() {
+        let expected =
+            find_positions_to_solve(&[Position::empty()], PlyTarget::Absolute(4)).unwrap();
+        let book = test_book(4);
+        let shard_entries = |shard: Shard| -> Vec<BookEntry> {
+            expected
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| shard.contains(*index))
+                .map(|(_, position)| BookEntry::new(position, book.get(position)))
+                .collect()
+        };
+        let shards: Vec<Vec<BookEntry>> = (1..=3)
+            .map(|index| shard_entries(Shard { index, count: 3 }))
+            .collect();
+        assert!(shards
+            .iter()
+            .all(|entries| entries.len() * 3 >= expected.len() - 2));
+
+        let all: Vec<BookEntry> = shards.concat();
+        let merged = combine_shard_entries(&expected, all.clone()).unwrap();
+        assert!(merged == book.iter().collect::<Vec<BookEntry>>());
+
+        // overlapping shards fail even if the scores agree
+        let overlapping = [all.clone(), shards[0].clone()].concat();
+        let result = combine_shard_entries(&expected, overlapping);
+        let count = shards[0].len();
+        assert!(matches!(
+            result,
+            Err(Error::ShardMismatch { duplicate_count, missing_count: 0, unexpected_count: 0 })
+                if duplicate_count == count
+        ));
+
+        let missing = [shards[0].clone(), shards[1].clone()].concat();
+        let result = combine_shard_entries(&expected, missing);
+        assert!(matches!(
+            result,
+            Err(Error::ShardMismatch { duplicate_count: 0, missing_count, .. }) if missing_count > 0
+        ));
+
+        let mut conflicting = all;
+        let entry = *shards[2]
+            .iter()
+            .find(|e| e.get_score() != Score::Draw)
+            .unwrap();
+        conflicting.push(entry.with_score(entry.get_score().flip()));
+        let result = combine_shard_entries(&expected, conflicting);
+        assert!(matches!(
+            result,
+            Err(Error::ShardMismatch {
+                duplicate_count: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn backup_matches_lower_ply_book() {
+        let source = solved_book("44444433", &[2]);
+        let mut backup = Backup::new(&source, 10);
+        let root = Position::from_variation("44444433").unwrap();
+        assert_eq!(backup.score(&root), Score::Win);
+        for entry in solved_book("44444433", &[1]).iter() {
+            assert_eq!(backup.score(&entry.get_position()), entry.get_score());
+        }
+        assert!(backup.missing.is_empty());
+
+        let book = sample_book();
+        let mut backup = Backup::new(&book, 4);
+        // moving to 1234 wins but moving to 4444 loses and other moves are not in the book
+        assert_eq!(
+            backup.score(&Position::from_variation("123").unwrap()),
+            Score::Win
+        );
+        assert_eq!(
+            backup.score(&Position::from_variation("444").unwrap()),
+            Score::Unknown
+        );
+        assert!(!backup.missing.is_empty());
+    }
+
+    #[test]
+    fn consistency() {
+        let book = solved_book("44444433", &[1, 2]);
+        let (inconsistencies, missing_count) = find_inconsistencies(&book);
+        assert!(inconsistencies.is_empty());
+        assert_eq!(missing_count, 0);
+
+        // 444444332 is a loss with perfect play so a bound that allows a loss is consistent
+        let position = Position::from_variation("444444332").unwrap();
+        let loss = book.get_entry(&position).unwrap();
+        assert_eq!(loss.get_score(), Score::Loss);
+        let with_score = |score: Score| {
+            let mut changed = Book::empty();
+            changed.add_entry(loss.with_score(score));
+            changed.merge(&book, ConflictPolicy::KeepReference).unwrap()
+        };
+        assert!(find_inconsistencies(&with_score(Score::DrawOrLoss))
+            .0
+            .is_empty());
+
+        let (inconsistencies, _) = find_inconsistencies(&with_score(Score::Win));
+        assert_eq!(inconsistencies.len(), 1);
+        assert!(inconsistencies[0].0.get_position() == position.normalize());
+        assert_eq!(inconsistencies[0].1, Score::Loss);
+    }
+
+    /// The only test that reads the books in the books folder. It passes without them.
+    #[test]
+    fn standard_books_are_consistent() {
+        let book = Book::ply(4)
+            .merge(&Book::ply(8), ConflictPolicy::Fail)
+            .unwrap();
+        let (inconsistencies, missing_count) = find_inconsistencies(&book);
+        assert!(inconsistencies.is_empty());
+        assert_eq!(missing_count, 0);
+    }
+
+    #[test]
+    fn reader_streams_every_format() {
+        let book = test_book(4);
+        let expected: Vec<BookEntry> = book.iter().collect();
+        let formats = [
+            BookFormat::Hex,
+            BookFormat::Binary,
+            BookFormat::Versioned,
+            BookFormat::Compressed,
+        ];
+        for format in formats {
+            let bytes = write_book(&book, format);
+            let read = |reader: BookReader<Cursor<&[u8]>>| -> Vec<BookEntry> {
+                reader.map(|entry| entry.unwrap()).collect()
+            };
+            assert!(read(BookReader::new(Cursor::new(&bytes[..]), format).unwrap()) == expected);
+            assert!(read(BookReader::autodetect(Cursor::new(&bytes[..])).unwrap()) == expected);
+        }
+
+        let bytes = write_book(&book, BookFormat::Vianiato);
+        let reader = BookReader::new(Cursor::new(&bytes[..]), BookFormat::Vianiato).unwrap();
+        assert_eq!(reader.count(), expected.len());
+    }
+
+    #[test]
+    fn reader_checks_versioned_books() {
+        for format in [BookFormat::Versioned, BookFormat::Compressed] {
+            let mut bytes = write_book(&sample_book(), format);
+            bytes[VersionedHeader::SIZE] ^= 1;
+            let mut reader = BookReader::new(Cursor::new(&bytes[..]), format).unwrap();
+            assert!(reader.any(|entry| entry.is_err()));
+        }
+
+        let bytes = write_book(&sample_book(), BookFormat::Versioned);
+        let truncated = &bytes[..VersionedHeader::SIZE];
+        let result = BookReader::new(Cursor::new(truncated), BookFormat::Versioned);
+        assert!(matches!(result, Err(Error::Format(_))));
+    }
+
+    #[test]
+    fn csv_sample_entries() {
+        let line = |cells: &[(usize, &str)], score: &str| {
+            let mut fields = vec!["b"; (BOARD_WIDTH * BOARD_HEIGHT) as usize];
+            for (index, cell) in cells {
+                fields[*index] = cell;
+            }
+            fields.push(score);
+            fields.join(",")
+        };
+
+        // 4444 is a win for the first player, who is to move
+        let sample = line(&[(18, "x"), (19, "o"), (20, "x"), (21, "o")], "win");
+        for perspective in [Perspective::FirstPlayer, Perspective::SideToMove] {
+            let entry = BookEntry::from_csv_string(&sample, perspective).unwrap();
+            assert!(entry.get_position() == Position::from_variation("4444").unwrap());
+            assert_eq!(entry.get_score(), Score::Win);
+            assert_eq!(entry.to_csv_string(perspective), sample);
+        }
+
+        // after 4, x is the first player or the player to move depending on the perspective
+        let sample = line(&[(18, "x")], "win");
+        let entry = BookEntry::from_csv_string(&sample, Perspective::FirstPlayer).unwrap();
+        assert!(entry.get_position() == Position::from_variation("4").unwrap());
+        assert_eq!(entry.get_score(), Score::Loss);
+        assert_eq!(entry.to_csv_string(Perspective::FirstPlayer), sample);
+        let entry = BookEntry::from_csv_string(&sample, Perspective::SideToMove).unwrap();
+        assert_eq!(entry.get_position().current.0.count_ones(), 1);
+        assert_eq!(entry.get_score(), Score::Win);
+
+        // the lenient parser of text books uses the first player's perspective
+        let entry = BookEntry::autodetect_parse(&sample).unwrap();
+        assert_eq!(entry.get_score(), Score::Loss);
+
+        assert!(BookEntry::from_csv_string(&line(&[], "won"), Perspective::FirstPlayer).is_none());
+        assert!(
+            BookEntry::from_csv_string(&line(&[(0, "z")], "win"), Perspective::FirstPlayer)
+                .is_none()
+        );
+        assert!(BookEntry::from_csv_string("b,b,win", Perspective::FirstPlayer).is_none());
+    }
+
+    #[test]
+    fn csv_dataset_lines() {
+        // the first lines of connect-4.data in the UCI Connect-4 dataset
+        let lines = [
+            (
+                "b,b,b,b,b,b,b,b,b,b,b,b,x,o,b,b,b,b,x,o,x,o,x,o,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,win",
+                "44444433",
+            ),
+            (
+                "b,b,b,b,b,b,b,b,b,b,b,b,x,b,b,b,b,b,x,o,x,o,x,o,o,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,win",
+                "44444435",
+            ),
+            (
+                "b,b,b,b,b,b,o,b,b,b,b,b,x,b,b,b,b,b,x,o,x,o,x,o,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,b,win",
+                "44444432",
+            ),
+        ];
+        let mut engine = Engine::new();
+        for (line, variation) in lines {
+            let position = Position::from_variation(variation).unwrap();
+            let entry = BookEntry::from_csv_string(line, Perspective::FirstPlayer).unwrap();
+            assert!(entry.get_position() == position.normalize());
+            engine.set_position(position);
+            assert_eq!(entry.get_score(), engine.solve());
+        }
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let mut book = Book::empty();
+        for variation in ["4444", "444", "1234", "123", "44444433333322222"] {
+            let position = Position::from_variation(variation).unwrap();
+            for score in [
+                Score::Unknown,
+                Score::Loss,
+                Score::DrawOrLoss,
+                Score::DrawOrWin,
+            ] {
+                book.add_entry(BookEntry::new(&position, score));
+            }
+        }
+        for perspective in [Perspective::FirstPlayer, Perspective::SideToMove] {
+            let bytes = write_book(&book, BookFormat::Csv(perspective));
+            let reader = BookReader::sequential(&bytes[..], BookFormat::Csv(perspective));
+            let entries: Vec<BookEntry> = reader.map(|entry| entry.unwrap()).collect();
+            assert!(entries == book.iter().collect::<Vec<BookEntry>>());
+        }
+    }
+
+    #[test]
+    fn lookup_index() {
+        let full_book = test_book(4);
+        let positions: Vec<Position> = full_book
+            .iter()
+            .flat_map(|entry| {
+                let position = entry.get_position();
+                // the children are not in the book
+                let children =
+                    (0..BOARD_WIDTH).filter_map(move |x| position.position_after_drop(x));
+                [position, position.flip()].into_iter().chain(children)
+            })
+            .collect();
+        for size in [0, 1, 2, 3, 100, full_book.len()] {
+            let mut book = Book::empty();
+            full_book
+                .iter()
+                .take(size)
+                .for_each(|entry| book.add_entry(entry));
+            let mut indexed_book = Book::empty();
+            indexed_book.include_book(&book);
+            indexed_book.build_lookup_index();
+            for position in &positions {
+                assert!(indexed_book.get_entry(position) == book.get_entry(position));
+            }
+        }
+
+        // modifying the book drops the index
+        let mut book = test_book(4);
+        book.build_lookup_index();
+        book.include_book(&test_book(5));
+        let position = test_book(5).iter().next().unwrap().get_position();
+        assert!(book.get_entry(&position).is_some());
+    }
+
+    #[test]
+    fn shared_book() {
+        let book = Arc::new(sample_book());
+        book.build_lookup_index();
+        let position = Position::from_variation("4444").unwrap();
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                let book = Arc::clone(&book);
+                scope.spawn(move || {
+                    let mut engine = Engine::new();
+                    engine.set_book(book);
+                    engine.set_position(position);
+                    assert_eq!(engine.solve(), Score::Win);
+                    // the search ends at the book position right away
+                    assert_eq!(engine.work_count, 1);
+                });
+            }
+        });
+
+        // the index is only built on request
+        let mut engine = Engine::new();
+        engine.set_book(sample_book());
+        assert!(!engine.get_book().unwrap().has_lookup_index());
+        let shared = engine.get_shared_book().unwrap();
+        let mut other_engine = Engine::new();
+        other_engine.set_book(Arc::clone(&shared));
+        assert!(Arc::ptr_eq(
+            &shared,
+            &other_engine.get_shared_book().unwrap()
+        ));
+    }
+
+    #[test]
+    fn readable_round_trip() {
+        let mut book = Book::empty();
+        for variation in ["", "4444", "444", "1234", "123", "44444433333322222"] {
+            let position = Position::from_variation(variation).unwrap();
+            book.add_entry(BookEntry::new(&position, Score::DrawOrWin));
+        }
+        let position = Position::from_variation("1123").unwrap();
+        book.add_entry(BookEntry::annotated(&position, Score::Loss, Some(3)));
+
+        let bytes = write_book(&book, BookFormat::Readable);
+        let reader = BookReader::sequential(&bytes[..], BookFormat::Readable);
+        let entries: Vec<BookEntry> = reader.map(|entry| entry.unwrap()).collect();
+        assert!(entries == book.iter().collect::<Vec<BookEntry>>());
+        assert_eq!(Book::from_bytes(&bytes).unwrap().len(), book.len());
+    }
+
+    #[test]
+    fn readable_sample_record() {
+        let record = "code: 0000040812A04081\n\
+            variation: 4444\n\
+            ply: 4\n\
+            to move: X\n\
+            score: win\n\
+            .......\n\
+            .......\n\
+            ...O...\n\
+            ...X...\n\
+            ...O...\n\
+            ...X...\n";
+        let entry = BookEntry::new(&Position::from_variation("4444").unwrap(), Score::Win);
+        assert_eq!(entry.to_readable_string(), record);
+        assert!(BookEntry::from_readable_string(record) == Some(entry));
+
+        // the diagram is enough
+        let diagram = record.lines().skip(5).collect::<Vec<&str>>().join("\n");
+        assert!(
+            BookEntry::from_readable_string(&format!("score: win\n{}", diagram)) == Some(entry)
+        );
+
+        // fields that disagree with the diagram are rejected
+        for field in [
+            "variation: 4445",
+            "ply: 5",
+            "to move: O",
+            "code: 0000040812A04082",
+        ] {
+            let changed = format!("{}\nscore: win\n{}", field, diagram);
+            assert!(BookEntry::from_readable_string(&changed).is_none());
+        }
+        assert!(BookEntry::from_readable_string(&diagram).is_none());
+    }
+
+    #[test]
+    fn vianiato_entries_without_scores() {
+        // bytes of 4453 as the writer wrote them before scores were supported: both bitboards
+        // shifted left by 2 with the low bits left as zero
+        let position = Position::from_variation("4453").unwrap().normalize();
+        let mut bytes = [0u8; 16];
+        bytes[0..8].copy_from_slice(&(position.current.0 << 2).to_be_bytes());
+        bytes[8..16].copy_from_slice(&(position.other.0 << 2).to_be_bytes());
+        let entry = BookEntry::from_vianiato_bytes(&bytes).unwrap();
+        assert!(entry.get_position() == position);
+        assert_eq!(entry.get_score(), Score::Unknown);
+        assert_eq!(entry.to_vianiato_bytes(), bytes);
+    }
+
+    #[test]
+    fn vianiato_sample_entries() {
+        // 4444 is a win for the first player, who is to move
+        let mut bytes = [0u8; 16];
+        bytes[0..8].copy_from_slice(&0x0000_0000_0280_0003u64.to_be_bytes());
+        bytes[8..16].copy_from_slice(&0x0000_0000_0500_0000u64.to_be_bytes());
+        let entry = BookEntry::from_vianiato_bytes(&bytes).unwrap();
+        assert!(entry.get_position() == Position::from_variation("4444").unwrap());
+        assert_eq!(entry.get_score(), Score::Win);
+        assert_eq!(entry.to_vianiato_bytes(), bytes);
+
+        // 4 is a win for the first player so it is a loss for the second player, who is to move
+        let mut bytes = [0u8; 16];
+        bytes[0..8].copy_from_slice(&0x0000_0000_0080_0003u64.to_be_bytes());
+        let entry = BookEntry::from_vianiato_bytes(&bytes).unwrap();
+        assert!(entry.get_position() == Position::from_variation("4").unwrap());
+        assert_eq!(entry.get_score(), Score::Loss);
+        assert_eq!(entry.to_vianiato_bytes(), bytes);
+
+        // a draw or win for the first player is a draw or loss for the second player
+        bytes[7] = 2;
+        bytes[15] = 2;
+        let entry = BookEntry::from_vianiato_bytes(&bytes).unwrap();
+        assert_eq!(entry.get_score(), Score::DrawOrLoss);
+
+        // a bound must be based on a draw
+        bytes[7] = 3;
+        assert!(BookEntry::from_vianiato_bytes(&bytes).is_none());
+        // both players cannot have a disc in the same cell
+        bytes[8..16].copy_from_slice(&0x0000_0000_0080_0000u64.to_be_bytes());
+        assert!(BookEntry::from_vianiato_bytes(&bytes).is_none());
+    }
+
+    #[test]
+    fn vianiato_round_trip() {
+        let scores = [
+            Score::Unknown,
+            Score::Loss,
+            Score::DrawOrLoss,
+            Score::Draw,
+            Score::DrawOrWin,
+            Score::Win,
+        ];
+        let mut book = Book::empty();
+        for variation in ["4444", "444", "1234", "123", "44444433333322222"] {
+            let position = Position::from_variation(variation).unwrap();
+            for score in scores {
+                book.add_entry(BookEntry::new(&position, score));
+            }
+        }
+        let bytes = write_book(&book, BookFormat::Vianiato);
+        let reader = BookReader::sequential(&bytes[..], BookFormat::Vianiato);
+        let entries: Vec<BookEntry> = reader.map(|entry| entry.unwrap()).collect();
+        assert!(entries == book.iter().collect::<Vec<BookEntry>>());
+
+        let ply5 = test_book(5);
+        let bytes = write_book(&ply5, BookFormat::Vianiato);
+        let ply5_vianiato = Book::read_vianiato_book(&mut BufReader::new(&bytes[..])).unwrap();
+        let diff = ply5.diff(&ply5_vianiato);
+        assert!(diff.conflicts.is_empty() && diff.shared_count == ply5.len());
+    }
+
+    #[test]
+    fn reader_reports_locations() {
+        let location_of = |result: Option<Result<BookEntry, Error>>| match result {
+            Some(Err(Error::InBook { location, .. })) => location,
+            _ => panic!("expected an error with a location"),
+        };
+
+        let text = "0000040812A04081+\n\nnot a position\n000004081040C103-\n";
+        let mut reader = BookReader::sequential(text.as_bytes(), BookFormat::Hex);
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(location_of(reader.next()), BookLocation::Line(3));
+        assert!(reader.next().is_none());
+        let result = Book::from_lines(text);
+        assert!(matches!(result, Err(Error::InBook { path: None, .. })));
+
+        // a record is reported at its first line
+        let mut bytes = write_book(&sample_book(), BookFormat::Readable);
+        bytes.extend(b"\n\ncode: 0000040812A04081\nscore: win\n");
+        let mut reader = BookReader::sequential(&bytes[..], BookFormat::Readable);
+        let record_lines = 11 + 1;
+        assert_eq!(
+            location_of(reader.nth(2)),
+            BookLocation::Line(2 * record_lines + 3)
+        );
+
+        let mut bytes = write_book(&sample_book(), BookFormat::Binary);
+        bytes.extend([0xFF; BookEntry::BYTE_COUNT]);
+        let mut reader = BookReader::sequential(&bytes[..], BookFormat::Binary);
+        assert_eq!(reader.by_ref().take_while(|entry| entry.is_ok()).count(), 2);
+        let mut reader = BookReader::sequential(&bytes[..], BookFormat::Binary);
+        assert_eq!(location_of(reader.nth(2)), BookLocation::Offset(16));
+
+        // an entry that is cut off is an error rather than the end of the book
+        for format in [BookFormat::Binary, BookFormat::Vianiato] {
+            let mut bytes = write_book(&sample_book(), format);
+            let entry_size = bytes.len() / 2;
+            bytes.truncate(bytes.len() - 3);
+            let mut reader = BookReader::sequential(&bytes[..], format);
+            assert_eq!(
+                location_of(reader.nth(1)),
+                BookLocation::Offset(entry_size as u64)
+            );
+        }
+        let bytes = write_book(&sample_book(), BookFormat::Binary);
+        assert!(Book::from_bytes(&bytes[..bytes.len() - 3]).is_err());
+
+        let mut bytes = write_book(&sample_book(), BookFormat::Versioned);
+        let second_entry = VersionedHeader::SIZE + BookEntry::BYTE_COUNT;
+        bytes[second_entry..second_entry + BookEntry::BYTE_COUNT].fill(0xFF);
+        let mut reader = BookReader::new(Cursor::new(&bytes[..]), BookFormat::Versioned).unwrap();
+        assert_eq!(
+            location_of(reader.nth(1)),
+            BookLocation::Offset(second_entry as u64)
+        );
+
+        let error = Error::InBook {
+            path: Some(PathBuf::from("book.txt")),
+            location: BookLocation::Line(3),
+            source: Box::new(Error::Format("Invalid position".to_string())),
+        };
+        assert_eq!(error.to_string(), "book.txt at line 3: Invalid position");
+    }
+
+    #[test]
+    fn count_differences_of_sorted_entries() {
+        // the unknown score is skipped so it does not conflict with the win
+        let book1 =
+            Book::from_lines("0000040812A04081+\n000004081040C103-\n0000040820E08081?\n").unwrap();
+        let book2 = Book::from_lines("0000040812A04081=\n0000040820E08081+\n").unwrap();
+        let counts = count_differences(book1.iter().map(Ok), book2.iter().map(Ok)).unwrap();
+        assert_eq!(counts.conflict_count, 1);
+        assert_eq!(counts.shared_count, 1);
+        assert_eq!(counts.only_in_first_count, 1);
+        assert_eq!(counts.only_in_second_count, 1);
+
+        let failing = vec![Err(Error::Format("broken".to_string()))].into_iter();
+        assert!(count_differences(book1.iter().map(Ok), failing).is_err());
+
+        // the first book has two entries for 4444 and the position is in both books only once
+        let duplicates = [
+            "0000040812A04081+",
+            "0000040812A04081+ 4",
+            "000004081040C103-",
+        ];
+        let entries1: Vec<BookEntry> = duplicates
+            .iter()
+            .map(|line| BookEntry::from_hex_string(line).unwrap())
+            .collect();
+        let counts = count_differences(entries1.into_iter().map(Ok), book2.iter().map(Ok)).unwrap();
+        assert_eq!(counts.conflict_count, 1);
+        assert_eq!(counts.shared_count, 1);
+        assert_eq!(counts.only_in_first_count, 1);
+        assert_eq!(counts.only_in_second_count, 1);
+    }
+
+    #[test]
+    fn merge_duplicate_entries() {
+        let entries = |lines: &[&str]| -> Vec<BookEntry> {
+            lines
+                .iter()
+                .map(|line| BookEntry::from_hex_string(line).unwrap())
+                .collect()
+        };
+        let merge = |lines: &[&str], policy| -> Vec<Result<BookEntry, Error>> {
+            MergeDuplicates::new(entries(lines).into_iter().map(Ok), policy).collect()
+        };
+        let values = |merged: Vec<Result<BookEntry, Error>>| -> Vec<BookEntry> {
+            merged.into_iter().map(|entry| entry.unwrap()).collect()
+        };
+
+        // the annotated entry is kept and a bound gives way to the exact score
+        let lines = [
+            "0000040812A04081+",
+            "0000040812A04081+ 4",
+            "000004081040C103-",
+        ];
+        assert!(values(merge(&lines, ConflictPolicy::Fail)) == entries(&lines[1..]));
+        let lines = ["0000040812A04081>", "0000040812A04081+"];
+        assert!(values(merge(&lines, ConflictPolicy::PreferExact)) == entries(&lines[1..]));
+
+        // the conflicting position is left out and reported after the other entries
+        let lines = [
+            "0000040812A04081+",
+            "0000040812A04081=",
+            "000004081040C103-",
+        ];
+        let mut merger =
+            MergeDuplicates::new(entries(&lines).into_iter().map(Ok), ConflictPolicy::Fail);
+        let merged: Vec<_> = merger.by_ref().collect();
+        assert_eq!(merged.len(), 2);
+        assert!(*merged[0].as_ref().unwrap() == entries(&lines[2..])[0]);
+        assert!(matches!(merged[1], Err(Error::Conflict { count: 1 })));
+        assert!(merger.conflicts() == [entries(&lines)[0].get_position()]);
+        let merged = merge(&lines, ConflictPolicy::KeepReference);
+        assert!(values(merged) == entries(&[lines[0], lines[2]]));
+    }
+
+    #[test]
+    fn external_positions_match_in_memory_positions() {
+        let roots = [Position::empty()];
+        let expected = find_positions_to_solve(&roots, PlyTarget::Absolute(5)).unwrap();
+        let sort_options = SortOptions {
+            chunk_size: 1000,
+            ..SortOptions::default()
+        };
+        let mut sorter = ExternalSorter::new(&sort_options);
+        explore_roots(&roots, PlyTarget::Absolute(5), &mut |position| {
+            sorter
+                .push(BookEntry::new(&position, Score::Unknown))
+                .unwrap();
+        })
+        .unwrap();
+        let sorted = sorter.finish_to_file().unwrap();
+        assert_eq!(sorted.count, expected.len());
+        let positions: Vec<Position> = sorted
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().get_position())
+            .collect();
+        assert!(positions.into_iter().eq(expected.into_iter()));
+    }
+
+    #[test]
+    fn external_generation_resumes_after_a_torn_line() {
+        let book = solved_book("44444433", &[1]);
+        let book_path = temp_path("external-book.txt");
+        let partial_path = get_partial_path(&book_path);
+        let first = book.iter().next().unwrap();
+        let torn = format!("{}\n{}", first.to_hex_string(), &first.to_hex_string()[..8]);
+        fs::write(&partial_path, torn).unwrap();
+
+        let mut options = GenerateOptions::standard(0);
+        options.roots = vec![Position::from_variation("44444433").unwrap()];
+        options.ply = PlyTarget::Relative(1);
+        options.out = Some(&book_path);
+        options.external = Some(SortOptions::default());
+        let result = generate_book(&options);
+        let generated = Book::open_with_format(&book_path, BookFormat::Hex);
+        let _ = fs::remove_file(&book_path);
+        let _ = fs::remove_file(&partial_path);
+        result.unwrap();
+        assert!(generated.unwrap().iter().eq(book.iter()));
+    }
+
+    #[test]
+    fn shard_position_count() {
+        for total_count in 0..10 {
+            for index in 1..=3 {
+                let shard = Shard { index, count: 3 };
+                let expected = (0..total_count).filter(|i| shard.contains(*i)).count();
+                assert_eq!(shard.get_position_count(total_count), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn versioned_checksum() {
+        let mut bytes = write_book(&sample_book(), BookFormat::Versioned);
+        bytes[VersionedHeader::SIZE] ^= 1;
+        let result = Book::read_versioned_book(&mut BufReader::new(&bytes[..]));
+        assert!(matches!(result, Err(Error::Format(_))));
+
+        // the header and footer are covered too, e.g. clearing the sorted flag
+        let mut bytes = write_book(&sample_book(), BookFormat::Versioned);
+        bytes[15] ^= FLAG_SORTED as u8;
+        let result = Book::read_versioned_book(&mut BufReader::new(&bytes[..]));
+        assert!(matches!(result, Err(Error::Format(message)) if message.contains("checksum")));
+    }
+
+    #[test]
+    fn versioned_ply_set_must_match_entries() {
+        for format in [BookFormat::Versioned, BookFormat::Compressed] {
+            let mut bytes = write_book(&sample_book(), format);
+            let footer_start = bytes.len() - VersionedFooter::SIZE;
+            let mut footer = VersionedFooter::from_bytes(&bytes[footer_start..]);
+            footer.ply_set |= 1 << 20;
+            let hash = fnv1a(FNV_OFFSET_BASIS, &bytes[..footer_start]);
+            footer.checksum = footer.compute_checksum(hash);
+            bytes[footer_start..].copy_from_slice(&footer.to_bytes());
+
+            let result = Book::read_versioned_book(&mut BufReader::new(&bytes[..]));
+            assert!(matches!(result, Err(Error::Format(message)) if message.contains("plies")));
+            let mut reader = BookReader::new(Cursor::new(&bytes[..]), format).unwrap();
+            assert!(reader.any(|entry| entry.is_err()));
+        }
+    }
+
+    /// A book of every position of the ply with made-up scores, so that tests get a book of a
+    /// realistic size without reading one from the books folder
+    pub(crate) fn test_book(ply: u32) -> Book {
+        let mut book = Book::empty();
+        let root = Position::empty();
+        for position in find_positions_to_solve(&[root], PlyTarget::Absolute(ply)).unwrap() {
+            let score = Score::from_u64_fast(position.to_position_code() % 5 + 1);
+            book.add_entry(BookEntry::new(&position, score));
+        }
+        book.sort_and_shrink();
+        book
+    }
+}
@@ -7,6 +7,7 @@ use crate::move_bitmap::MoveBitmap;
 use crate::position::Position;
 use crate::score::Score;
 use crate::trans_table::TransTable;
+use std::sync::Arc;
 
 pub struct Engine {
     pub position: Position,
@@ -14,7 +15,7 @@ pub struct Engine {
     pub work_count: usize,
     pub heuristic: FixedHeuristic,
     ply: u32,
-    book: Option<Box<Book>>,
+    book: Option<Arc<Book>>,
 }
 
 #[derive(Clone, Copy)]
@@ -76,8 +77,20 @@ impl Engine {
         }
     }
 
-    pub fn set_book(&mut self, book: Box<Book>) {
-        self.book = Some(book);
+    /// Accepts an Arc so that several engines, e.g. ones that solve in parallel, can share a book
+    /// that is loaded only once. The search looks up positions in its hot path, so call
+    /// Book::build_lookup_index first if the engine searches long enough to make up for the memory.
+    pub fn set_book(&mut self, book: impl Into<Arc<Book>>) {
+        self.book = Some(book.into());
+    }
+
+    /// Returns a handle to the book that can be given to other engines
+    pub fn get_shared_book(&self) -> Option<Arc<Book>> {
+        self.book.clone()
+    }
+
+    pub fn get_book(&self) -> Option<&Book> {
+        self.book.as_deref()
     }
 
     pub fn reset(&mut self) {
@@ -0,0 +1,302 @@
+use crate::bitboard::BoardInteger;
+use crate::score::Score;
+use std::fmt::Formatter;
+use std::path::PathBuf;
+use std::{fmt, io};
+
+/// Describes why a position or a bitboard could not be parsed. Indexes are zero-based and refer to
+/// characters of the trimmed input whereas columns and rows are shown one-based to the user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A character in a variation that does not name any column
+    InvalidColumn { index: usize, character: char },
+    /// A move was made in a column that has no empty cells left
+    ColumnFull { index: usize, column: u32 },
+    /// A move was made after one of the players already had four in a row
+    MoveAfterWin { index: usize, column: u32 },
+    /// A board diagram contains a character other than the allowed ones
+    InvalidCharacter { x: u32, y: u32, character: char },
+    /// A board diagram has more rows or columns than the board
+    CellOutOfBounds { x: u32, y: u32 },
+    /// A disc in a board diagram has an empty cell directly below it
+    FloatingDisc { x: u32, y: u32 },
+    /// A hex code that is too short or too long
+    InvalidHexLength { length: usize },
+    /// A character in a hex code that is not a hexadecimal digit
+    InvalidHexDigit { index: usize, character: char },
+    /// A position code where some column lacks the bit that indicates its height
+    InvalidPositionCode { code: BoardInteger },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidColumn { index, character } => write!(
+                f,
+                "'{}' at index {} is not a valid column",
+                character, index
+            ),
+            ParseError::ColumnFull { index, column } => write!(
+                f,
+                "column {} at index {} is already full",
+                column + 1,
+                index
+            ),
+            ParseError::MoveAfterWin { index, column } => write!(
+                f,
+                "move in column {} at index {} was made after the game was already won",
+                column + 1,
+                index
+            ),
+            ParseError::InvalidCharacter { x, y, character } => write!(
+                f,
+                "'{}' at column {} and row {} is not a valid cell",
+                character,
+                x + 1,
+                y + 1
+            ),
+            ParseError::CellOutOfBounds { x, y } => write!(
+                f,
+                "cell at column {} and row {} is outside the board",
+                x + 1,
+                y + 1
+            ),
+            ParseError::FloatingDisc { x, y } => write!(
+                f,
+                "disc at column {} and row {} has an empty cell below it",
+                x + 1,
+                y + 1
+            ),
+            ParseError::InvalidHexLength { length } => write!(
+                f,
+                "hex code has {} characters but {} were expected",
+                length,
+                2 * std::mem::size_of::<BoardInteger>()
+            ),
+            ParseError::InvalidHexDigit { index, character } => write!(
+                f,
+                "'{}' at index {} is not a hexadecimal digit",
+                character, index
+            ),
+            ParseError::InvalidPositionCode { code } => {
+                write!(f, "{:0>16X} is not a valid position code", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A reason why a position cannot occur in a real game even though it can be represented
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Both players have a disc in the same cell
+    OverlappingDisc { x: u32, y: u32 },
+    /// A disc has an empty cell directly below it
+    FloatingDisc { x: u32, y: u32 },
+    /// The player who moved last must have the same number of discs as the player to move or one
+    /// more
+    DiscCountMismatch { current: u32, other: u32 },
+    /// Both players have four in a row
+    BothPlayersWon,
+    /// The player to move already has four in a row so the other player moved after the game ended
+    MoveAfterWin,
+    /// The position is otherwise consistent but no order of moves leads to it without continuing a
+    /// game that was already won
+    Unreachable,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::OverlappingDisc { x, y } => write!(
+                f,
+                "both players have a disc at column {} and row {}",
+                x + 1,
+                y + 1
+            ),
+            ValidationError::FloatingDisc { x, y } => write!(
+                f,
+                "disc at column {} and row {} has an empty cell below it",
+                x + 1,
+                y + 1
+            ),
+            ValidationError::DiscCountMismatch { current, other } => write!(
+                f,
+                "the player to move has {} discs but the other player has {}",
+                current, other
+            ),
+            ValidationError::BothPlayersWon => write!(f, "both players have four in a row"),
+            ValidationError::MoveAfterWin => {
+                write!(f, "the player to move already has four in a row")
+            }
+            ValidationError::Unreachable => write!(
+                f,
+                "no sequence of moves reaches the position without playing after a win"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Where an entry starts in a book file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BookLocation {
+    /// A one-based line number in a text book
+    Line(usize),
+    /// A byte offset from the start of a binary book, including any header
+    Offset(u64),
+}
+
+impl fmt::Display for BookLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BookLocation::Line(line) => write!(f, "line {}", line),
+            BookLocation::Offset(offset) => write!(f, "byte {}", offset),
+        }
+    }
+}
+
+/// The error type for everything in this crate that can fail
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(ParseError),
+    /// A position that can be represented but cannot occur in a real game
+    Validation {
+        code: BoardInteger,
+        errors: Vec<ValidationError>,
+    },
+    /// Wraps another error with the input that caused it so that errors can be reported using the
+    /// same string that the user gave
+    InvalidInput {
+        input: String,
+        source: Box<Error>,
+    },
+    /// Wraps an error that happened while reading an entry of a book. The path is known if the
+    /// book was opened from a file.
+    InBook {
+        path: Option<PathBuf>,
+        location: BookLocation,
+        source: Box<Error>,
+    },
+    /// A book or test file whose content is malformed
+    Format(String),
+    /// A format name that is not recognized
+    UnknownFormat(String),
+    /// Books that have different scores for the same positions
+    Conflict {
+        count: usize,
+    },
+    /// Book entries whose scores differ from the ones that the engine finds
+    AuditMismatch {
+        count: usize,
+    },
+    /// Book entries whose scores contradict the scores of their children
+    Inconsistent {
+        count: usize,
+    },
+    /// Shards of a book that do not have exactly the expected positions once
+    ShardMismatch {
+        duplicate_count: usize,
+        missing_count: usize,
+        unexpected_count: usize,
+    },
+    /// The engine found a different score than what was expected
+    ScoreMismatch {
+        code: BoardInteger,
+        expected: Score,
+        actual: Score,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse(err) => write!(f, "{}", err),
+            Error::Validation { code, errors } => {
+                write!(f, "position {:0>16X} cannot occur in a real game: ", code)?;
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", err)?;
+                }
+                Ok(())
+            }
+            Error::InvalidInput { input, source } => {
+                write!(f, "Invalid input {}: {}", input, source)
+            }
+            Error::InBook {
+                path,
+                location,
+                source,
+            } => match path {
+                Some(path) => write!(f, "{} at {}: {}", path.display(), location, source),
+                None => write!(f, "Book at {}: {}", location, source),
+            },
+            Error::Format(message) => write!(f, "{}", message),
+            Error::UnknownFormat(name) => write!(f, "Unknown format: {}", name),
+            Error::Conflict { count } => {
+                write!(f, "{} positions with conflicting scores", count)
+            }
+            Error::AuditMismatch { count } => {
+                write!(
+                    f,
+                    "{} positions have a different score than the engine finds",
+                    count
+                )
+            }
+            Error::Inconsistent { count } => write!(
+                f,
+                "{} positions have scores that contradict the scores of their children",
+                count
+            ),
+            Error::ShardMismatch {
+                duplicate_count,
+                missing_count,
+                unexpected_count,
+            } => write!(
+                f,
+                "{} positions are in more than one shard, {} are missing and {} are unexpected",
+                duplicate_count, missing_count, unexpected_count
+            ),
+            Error::ScoreMismatch {
+                code,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Expected score {:?} but got {:?} for position {:0>16X}",
+                expected, actual, code
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Parse(err) => Some(err),
+            Error::InvalidInput { source, .. } | Error::InBook { source, .. } => {
+                Some(source.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
@@ -0,0 +1,282 @@
+use crate::bitboard::BoardInteger;
+use crate::book::{BookEntry, BookFormat, BookReader, BookWriter};
+use crate::error::Error;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::env;
+use std::fs::{remove_file, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::vec;
+
+/// The number of entries that are sorted in memory at a time. Each entry takes 8 bytes.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 22;
+
+/// Where and in how large chunks entries that do not fit in memory are sorted
+#[derive(Clone, Debug)]
+pub struct SortOptions {
+    pub temp_dir: PathBuf,
+    pub chunk_size: usize,
+}
+
+impl Default for SortOptions {
+    fn default() -> Self {
+        SortOptions {
+            temp_dir: env::temp_dir(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+/// A file that is removed when it is dropped
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(temp_dir: &Path) -> Result<(TempFile, File), Error> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "fourengine-{}-{}.tmp",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = temp_dir.join(name);
+        let file = File::create(&path)?;
+        Ok((TempFile { path }, file))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// Sorts book entries with a bounded amount of memory. Entries are collected into chunks that are
+/// sorted in memory and written to temporary files, which are then merged while reading. Entries
+/// that are exactly the same are returned only once, but entries that have the same position and
+/// a different score or annotations are all kept.
+pub struct ExternalSorter {
+    options: SortOptions,
+    chunk: Vec<BookEntry>,
+    runs: Vec<TempFile>,
+}
+
+impl ExternalSorter {
+    pub fn new(options: &SortOptions) -> ExternalSorter {
+        ExternalSorter {
+            options: options.clone(),
+            chunk: vec![],
+            runs: vec![],
+        }
+    }
+
+    pub fn push(&mut self, entry: BookEntry) -> Result<(), Error> {
+        self.chunk.push(entry);
+        if self.chunk.len() >= self.options.chunk_size.max(1) {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self) -> Result<(), Error> {
+        sort_chunk(&mut self.chunk);
+        let (run, file) = TempFile::create(&self.options.temp_dir)?;
+        let mut book_writer = BookWriter::create(BufWriter::new(file), BookFormat::Binary);
+        for entry in &self.chunk {
+            book_writer.write_entry(entry)?;
+        }
+        book_writer.finish()?;
+        self.chunk.clear();
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// Returns the entries in ascending order of position codes. Nothing is written to disk if all
+    /// entries fit in one chunk.
+    pub fn finish(mut self) -> Result<SortedEntries, Error> {
+        if self.runs.is_empty() {
+            sort_chunk(&mut self.chunk);
+            return Ok(SortedEntries::Memory(self.chunk.into_iter()));
+        }
+        if !self.chunk.is_empty() {
+            self.write_run()?;
+        }
+
+        let mut readers = vec![];
+        for run in &self.runs {
+            readers.push(BookReader::open_with_format(&run.path, BookFormat::Binary)?);
+        }
+        let mut merge = RunMerge {
+            readers,
+            heap: BinaryHeap::new(),
+            previous: None,
+            _runs: self.runs,
+        };
+        for index in 0..merge.readers.len() {
+            merge.fill(index)?;
+        }
+        Ok(SortedEntries::Runs(merge))
+    }
+
+    /// Writes the sorted entries into one temporary file so that they can be counted and then read
+    /// as many times as needed
+    pub fn finish_to_file(self) -> Result<SortedFile, Error> {
+        let temp_dir = self.options.temp_dir.clone();
+        let entries = self.finish()?;
+        let (file, writer) = TempFile::create(&temp_dir)?;
+        let mut book_writer = BookWriter::create_sorted(BufWriter::new(writer), BookFormat::Binary);
+        let mut count = 0;
+        for entry in entries {
+            book_writer.write_entry(&entry?)?;
+            count += 1;
+        }
+        book_writer.finish()?;
+        Ok(SortedFile { file, count })
+    }
+}
+
+/// Entries that ExternalSorter has sorted into a temporary file
+pub struct SortedFile {
+    file: TempFile,
+    pub count: usize,
+}
+
+impl SortedFile {
+    pub fn entries(&self) -> Result<BookReader<File>, Error> {
+        BookReader::open_with_format(&self.file.path, BookFormat::Binary)
+    }
+}
+
+fn sort_chunk(chunk: &mut Vec<BookEntry>) {
+    chunk.sort_unstable_by_key(sort_key);
+    chunk.dedup();
+}
+
+/// Orders entries by position code and then by the whole entry so that duplicates are next to each
+/// other
+type SortKey = (BoardInteger, [u8; 8]);
+
+fn sort_key(entry: &BookEntry) -> SortKey {
+    (entry.get_position_code(), entry.to_bytes())
+}
+
+/// Entries returned by ExternalSorter in ascending order of position codes
+pub enum SortedEntries {
+    Memory(vec::IntoIter<BookEntry>),
+    Runs(RunMerge),
+}
+
+impl Iterator for SortedEntries {
+    type Item = Result<BookEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedEntries::Memory(iter) => iter.next().map(Ok),
+            SortedEntries::Runs(merge) => merge.next(),
+        }
+    }
+}
+
+/// Merges sorted temporary files by always taking the smallest of their next entries
+pub struct RunMerge {
+    readers: Vec<BookReader<File>>,
+    heap: BinaryHeap<Reverse<(SortKey, usize)>>,
+    previous: Option<BookEntry>,
+    /// Removes the files when the merge is dropped
+    _runs: Vec<TempFile>,
+}
+
+impl RunMerge {
+    /// Reads the next entry of a run into the heap
+    fn fill(&mut self, index: usize) -> Result<(), Error> {
+        if let Some(entry) = self.readers[index].next().transpose()? {
+            self.heap.push(Reverse((sort_key(&entry), index)));
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Option<Result<BookEntry, Error>> {
+        loop {
+            let Reverse(((_, bytes), index)) = self.heap.pop()?;
+            if let Err(err) = self.fill(index) {
+                self.heap.clear();
+                return Some(Err(err));
+            }
+            // runs were written from valid entries
+            let entry = BookEntry::from_bytes(&bytes).unwrap();
+            if self.previous != Some(entry) {
+                self.previous = Some(entry);
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::tests::test_book;
+    use crate::position::Position;
+    use crate::score::Score;
+
+    fn sort(entries: &[BookEntry], chunk_size: usize) -> Vec<BookEntry> {
+        let options = SortOptions {
+            chunk_size,
+            ..SortOptions::default()
+        };
+        let mut sorter = ExternalSorter::new(&options);
+        for entry in entries {
+            sorter.push(*entry).unwrap();
+        }
+        sorter
+            .finish()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sorts_in_chunks() {
+        let expected: Vec<BookEntry> = test_book(4).iter().collect();
+        let mut entries = expected.clone();
+        entries.reverse();
+        // every entry twice
+        entries.extend(expected.iter());
+
+        assert!(sort(&entries, 1000000) == expected);
+        assert!(sort(&entries, 100) == expected);
+        assert!(sort(&entries, 7) == expected);
+    }
+
+    #[test]
+    fn sorted_file_can_be_read_again() {
+        let options = SortOptions {
+            chunk_size: 100,
+            ..SortOptions::default()
+        };
+        let mut sorter = ExternalSorter::new(&options);
+        let expected: Vec<BookEntry> = test_book(4).iter().collect();
+        for entry in expected.iter().rev() {
+            sorter.push(*entry).unwrap();
+        }
+        let sorted = sorter.finish_to_file().unwrap();
+        assert_eq!(sorted.count, expected.len());
+        for _ in 0..2 {
+            let entries: Vec<BookEntry> = sorted.entries().unwrap().map(|e| e.unwrap()).collect();
+            assert!(entries == expected);
+        }
+    }
+
+    #[test]
+    fn keeps_different_entries_of_same_position() {
+        let position = Position::from_variation("4444").unwrap();
+        let win = BookEntry::new(&position, Score::Win);
+        let draw = BookEntry::new(&position, Score::Draw);
+        let sorted = sort(&[win, draw, win, draw], 1);
+        assert_eq!(sorted.len(), 2);
+    }
+}
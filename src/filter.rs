@@ -0,0 +1,196 @@
+use crate::book::{BookEntry, Random};
+use crate::position::Position;
+use crate::score::Score;
+use std::collections::BinaryHeap;
+
+/// Chooses entries of a book by their positions and scores. An entry is kept only if it passes
+/// every condition that is set, so the conditions can be combined freely.
+#[derive(Clone, Debug, Default)]
+pub struct BookFilter {
+    /// Keeps only these plies if not empty
+    pub plies: Vec<u32>,
+    /// Keeps only these scores if not empty. Scores are for the player to move like in books.
+    pub scores: Vec<Score>,
+    /// Keeps only positions that are their own mirror image
+    pub symmetric_only: bool,
+    /// Keeps only positions that can be reached by playing moves from this position. Books store
+    /// one of each pair of mirrored positions, so a position is kept if either one is reachable.
+    pub root: Option<Position>,
+    /// Leaves out positions where either player has already won
+    pub omit_won: bool,
+    /// Leaves out positions where the opponent threatens to win immediately
+    pub omit_forced: bool,
+}
+
+impl BookFilter {
+    pub fn is_included(&self, entry: &BookEntry) -> bool {
+        let position = entry.get_position();
+
+        if !self.plies.is_empty() && !self.plies.contains(&position.get_ply()) {
+            return false;
+        }
+
+        if !self.scores.is_empty() && !self.scores.contains(&entry.get_score()) {
+            return false;
+        }
+
+        if self.symmetric_only && position.flip() != position {
+            return false;
+        }
+
+        if self.omit_won && position.has_anyone_won() {
+            return false;
+        }
+
+        if self.omit_forced {
+            let enemy_threats = position.to_other_perspective().get_immediate_wins();
+            let is_forced_move = enemy_threats.0 != 0;
+            if is_forced_move {
+                return false;
+            }
+        }
+
+        // the slowest check is last
+        if let Some(root) = &self.root {
+            let reachable = |position: Position| position.guess_variation_from(root).is_some();
+            if !reachable(position) && !reachable(position.flip()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Chooses a pseudo-random sample of a fixed size while keeping only the sample in memory. Which
+/// entries are chosen depends only on the seed and the entries themselves and not on their order,
+/// so the same seed chooses the same sample from a book in any format. Duplicates should be
+/// removed beforehand because identical entries are always either chosen or left out together.
+pub struct Sampler {
+    size: usize,
+    seed: u64,
+    /// The chosen entries as integers, keyed by a hash of the entry. The entries with the
+    /// smallest keys are kept so the largest one is at the top of the heap.
+    heap: BinaryHeap<(u64, u64)>,
+}
+
+impl Sampler {
+    pub fn new(size: usize, seed: u64) -> Sampler {
+        Sampler {
+            size,
+            seed,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn push(&mut self, entry: BookEntry) {
+        let value = u64::from_be_bytes(entry.to_bytes());
+        let item = (Random(self.seed ^ value).next(), value);
+        if self.heap.len() < self.size {
+            self.heap.push(item);
+        } else if self.heap.peek().is_some_and(|largest| item < *largest) {
+            self.heap.pop();
+            self.heap.push(item);
+        }
+    }
+
+    /// Returns the chosen entries in ascending order of position codes
+    pub fn finish(self) -> Vec<BookEntry> {
+        let mut entries: Vec<BookEntry> = self
+            .heap
+            .into_iter()
+            // the values came from valid entries
+            .map(|(_, value)| BookEntry::from_bytes(&value.to_be_bytes()).unwrap())
+            .collect();
+        entries.sort_unstable_by_key(|entry| (entry.get_position_code(), entry.to_bytes()));
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::tests::test_book;
+    use crate::book::Book;
+
+    fn filter_book(book: &Book, filter: &BookFilter) -> Vec<BookEntry> {
+        book.iter()
+            .filter(|entry| filter.is_included(entry))
+            .collect()
+    }
+
+    #[test]
+    fn combined_filters() {
+        let book = test_book(4);
+        assert_eq!(filter_book(&book, &BookFilter::default()).len(), book.len());
+
+        let wins = BookFilter {
+            scores: vec![Score::Win],
+            ..BookFilter::default()
+        };
+        let symmetric_wins = BookFilter {
+            symmetric_only: true,
+            ..wins.clone()
+        };
+        let win_count = filter_book(&book, &wins).len();
+        let symmetric_entries = filter_book(&book, &symmetric_wins);
+        assert!(!symmetric_entries.is_empty() && symmetric_entries.len() < win_count);
+        for entry in symmetric_entries {
+            assert_eq!(entry.get_score(), Score::Win);
+            assert_eq!(entry.get_position().flip(), entry.get_position());
+        }
+
+        let other_ply = BookFilter {
+            plies: vec![5],
+            ..BookFilter::default()
+        };
+        assert!(filter_book(&book, &other_ply).is_empty());
+    }
+
+    #[test]
+    fn reachable_positions() {
+        let book = test_book(4);
+        let filter = BookFilter {
+            root: Some(Position::from_variation("44").unwrap()),
+            ..BookFilter::default()
+        };
+        let mut expected = vec![];
+        for a in 1..=7 {
+            for b in 1..=7 {
+                let position = Position::from_variation(&format!("44{}{}", a, b)).unwrap();
+                expected.push(BookEntry::new(&position, book.get(&position)));
+            }
+        }
+        expected.sort();
+        expected.dedup();
+        assert!(filter_book(&book, &filter) == expected);
+
+        // the entry has only one of the mirrored positions
+        let entry = BookEntry::new(&Position::from_variation("6623").unwrap(), Score::Unknown);
+        for root in ["66", "22"] {
+            let filter = BookFilter {
+                root: Some(Position::from_variation(root).unwrap()),
+                ..BookFilter::default()
+            };
+            assert!(filter.is_included(&entry));
+        }
+    }
+
+    #[test]
+    fn sample_is_deterministic() {
+        let sample = |entries: &mut dyn Iterator<Item = BookEntry>, seed| {
+            let mut sampler = Sampler::new(50, seed);
+            entries.for_each(|entry| sampler.push(entry));
+            sampler.finish()
+        };
+        let book = test_book(4);
+        let first = sample(&mut book.iter(), 1);
+        assert_eq!(first.len(), 50);
+        let mut reversed: Vec<BookEntry> = book.iter().collect();
+        reversed.reverse();
+        assert!(first == sample(&mut reversed.into_iter(), 1));
+        assert!(first != sample(&mut book.iter(), 2));
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(sample(&mut book.iter().take(10), 1).len(), 10);
+    }
+}
@@ -2,8 +2,15 @@ pub mod benchmark;
 pub mod bitboard;
 pub mod book;
 pub mod engine;
+pub mod error;
+pub mod external_sort;
+pub mod filter;
 mod heuristic;
 pub mod move_bitmap;
 pub mod position;
 pub mod score;
+pub mod stats;
+pub mod strategy;
 pub mod trans_table;
+
+pub use crate::error::Error;
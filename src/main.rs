@@ -1,19 +1,62 @@
-use clap::{crate_version, Arg, ArgMatches, Command, ArgAction};
+use clap::parser::ValueSource;
+use clap::{crate_version, value_parser, Arg, ArgAction, ArgMatches, Command};
 use fourengine::benchmark::Benchmark;
 use fourengine::bitboard::{Bitboard};
 use fourengine::book::{
-    generate_book, get_path_for_ply, verify_book, Book, BookFormat, BookWriter, DEFAULT_BOOK_PLY,
+    audit_book, check_book_consistency, derive_book, generate_book, get_path_for_ply,
+    get_versioned_path_for_ply, merge_shards, verify_book, AuditOptions, Book, BookDiff, BookEntry,
+    BookFormat, BookReader, BookWriter, ConflictPolicy, GenerateOptions, MergeDuplicates,
+    PlyTarget, DEFAULT_BOOK_PLY,
 };
 use fourengine::engine::Engine;
+use fourengine::external_sort::{ExternalSorter, SortOptions};
+use fourengine::filter::{BookFilter, Sampler};
 use fourengine::score::Score;
+use fourengine::stats::{BookStats, PlyStats, SCORES};
+use fourengine::strategy::extract_strategy;
+use fourengine::Error;
 use std::cmp::Ordering;
 use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader, LineWriter, Write};
 use std::path::Path;
+use std::process::exit;
 use fourengine::position::Position;
 
+/// The names of the book formats that BookFormat can parse
+const BOOK_FORMATS: [&str; 8] = [
+    "hex",
+    "binary",
+    "vianiato",
+    "versioned",
+    "compressed",
+    "csv",
+    "csv-side-to-move",
+    "readable",
+];
+/// The book formats of books that are read, where the format can also be detected
+const DETECTABLE_BOOK_FORMATS: [&str; 9] = [
+    "detect",
+    "hex",
+    "binary",
+    "vianiato",
+    "versioned",
+    "compressed",
+    "csv",
+    "csv-side-to-move",
+    "readable",
+];
+/// The names of the scores that Score::from_string parses, as in Score::to_word
+const SCORE_NAMES: [&str; 6] = [
+    "win",
+    "loss",
+    "draw",
+    "draw-or-win",
+    "draw-or-loss",
+    "unknown",
+];
+
 /// User input representing a position. The purpose of this is to be able to report errors using
 /// the same string that the user gave. Using Position directly would lose that information.
 enum PositionInput {
@@ -22,19 +65,32 @@ enum PositionInput {
 }
 
 impl PositionInput {
-    fn parse(&self) -> Result<Position, String> {
-        match self {
+    /// Parses the input and, if requested, also checks that the position can occur in a real game
+    fn parse(&self, validate: bool) -> Result<Position, Error> {
+        self.parse_and_validate(validate)
+            .map_err(|err| Error::InvalidInput {
+                input: self.to_string().trim().to_string(),
+                source: Box::new(err),
+            })
+    }
+
+    fn parse_and_validate(&self, validate: bool) -> Result<Position, Error> {
+        let position = match self {
             Self::Variation(str) => Position::from_variation(str)
                 // Automatically try hex for convenience. In the standard board size, hex codes
                 // always start with leading zeroes which cannot happen in variations. There might
                 // be strings in other board sizes that are valid in both formats but for those
                 // situations the user can explicitly use --hex
-                .or_else(|| Position::from_hex_string(str))
-                .ok_or(format!("Invalid variation: {}", str)),
-            Self::Hex(str) => {
-                Position::from_hex_string(str).ok_or(format!("Invalid hex code: {}", str))
-            }
+                .or_else(|err| Position::from_hex_string(str).map_err(|_| err))?,
+            Self::Hex(str) => Position::from_hex_string(str)?,
+        };
+        if validate {
+            position.validate().map_err(|errors| Error::Validation {
+                code: position.to_position_code(),
+                errors,
+            })?;
         }
+        Ok(position)
     }
 }
 
@@ -47,10 +103,10 @@ impl fmt::Display for PositionInput {
     }
 }
 
-fn run_test_files(filenames: &[String]) -> Result<(), String> {
+fn run_test_files(filenames: &[String], validate: bool) -> Result<(), Error> {
     let mut total_benchmark = Benchmark::empty();
     for filename in filenames {
-        let benchmark = verify_and_benchmark_file(filename)?;
+        let benchmark = verify_and_benchmark_file(filename, validate)?;
         total_benchmark = total_benchmark.add(&benchmark)
     }
     total_benchmark.print();
@@ -58,13 +114,13 @@ fn run_test_files(filenames: &[String]) -> Result<(), String> {
     Ok(())
 }
 
-fn verify_and_benchmark_file(filename: &str) -> Result<Benchmark, String> {
-    let file = File::open(filename).map_err(|e| e.to_string())?;
+fn verify_and_benchmark_file(filename: &str, validate: bool) -> Result<Benchmark, Error> {
+    let file = File::open(filename)?;
     let reader = BufReader::new(file);
     let mut total_benchmark = Benchmark::empty();
     let mut engine = Engine::new();
     for line in reader.lines() {
-        if let Some((pos_input, score)) = parse_line_with_score(line.map_err(|e| e.to_string())?) {
+        if let Some((pos_input, score)) = parse_line_with_score(line?)? {
             println!(
                 "Expecting score {:<4} for variation {}",
                 format!("{:?}", score),
@@ -72,9 +128,16 @@ fn verify_and_benchmark_file(filename: &str) -> Result<Benchmark, String> {
             );
             //engine.reset();
             engine.work_count = 0;
-            engine.set_position(pos_input.parse()?);
+            let position = pos_input.parse(validate)?;
+            engine.set_position(position);
             let benchmark = Benchmark::run(&mut engine);
-            assert_eq!(benchmark.score, score, "Invalid score");
+            if benchmark.score != score {
+                return Err(Error::ScoreMismatch {
+                    code: position.to_position_code(),
+                    expected: score,
+                    actual: benchmark.score,
+                });
+            }
             total_benchmark = total_benchmark.add(&benchmark);
         }
     }
@@ -82,10 +145,15 @@ fn verify_and_benchmark_file(filename: &str) -> Result<Benchmark, String> {
     Ok(total_benchmark)
 }
 
-fn parse_line_with_score(line: String) -> Option<(PositionInput, Score)> {
+fn parse_line_with_score(line: String) -> Result<Option<(PositionInput, Score)>, Error> {
     let mut iter = line.split_whitespace();
-    let variation = String::from(iter.next()?);
-    let score_value = iter.next()?.parse::<i32>().unwrap();
+    let (variation, score_str) = match (iter.next(), iter.next()) {
+        (Some(variation), Some(score_str)) => (String::from(variation), score_str),
+        _ => return Ok(None),
+    };
+    let score_value = score_str
+        .parse::<i32>()
+        .map_err(|_| Error::Format(format!("Invalid score in test file: {}", line)))?;
 
     let score = match score_value.cmp(&0) {
         Ordering::Less => Score::Loss,
@@ -93,52 +161,72 @@ fn parse_line_with_score(line: String) -> Option<(PositionInput, Score)> {
         Ordering::Greater => Score::Win,
     };
 
-    Some((PositionInput::Variation(variation), score))
+    Ok(Some((PositionInput::Variation(variation), score)))
 }
 
-pub fn format_book(matches: &ArgMatches) -> Result<(), std::io::Error> {
-    let book_file = get_path_arg(&matches, "in").unwrap();
-    let book = match get_string_arg(&matches, "in-format").unwrap() {
-      "detect" => Book::open(book_file),
-      "binary" => Book::open_with_format(book_file, BookFormat::Binary),
-      "hex" => Book::open_with_format(book_file, BookFormat::Hex),
-      "vianiato" => Book::open_with_format(book_file, BookFormat::Vianiato),
-      &_ => panic!("Invalid in-format"),
+/// Streams the book so that books larger than memory can be converted. Entries are sorted on disk
+/// if there are too many to sort in memory.
+pub fn format_book(matches: &ArgMatches) -> Result<(), Error> {
+    let book_file = get_path_arg(matches, "in").unwrap();
+    let reader = match get_string_arg(matches, "in-format").unwrap() {
+        "detect" => BookReader::open(book_file),
+        name => BookReader::open_with_format(book_file, name.parse()?),
     }?;
+    let validate = matches.get_flag("validate");
 
-    let book_format = match get_string_arg(&matches, "out-format").unwrap() {
-        "binary" => BookFormat::Binary,
-        "hex" => BookFormat::Hex,
-        "vianiato" => BookFormat::Vianiato,
-        &_ => panic!("Invalid out-format"),
-    };
+    let book_format: BookFormat = get_string_arg(matches, "out-format").unwrap().parse()?;
 
-    let omit_won = matches.get_flag("omit-won");
-    let omit_forced = matches.get_flag("omit-forced");
-    let filtered_entries = book.iter().filter(|entry| {
-        let position = entry.get_position();
+    let filter = get_book_filter(matches)?;
+    let skip_invalid = matches.get_flag("skip-invalid");
+    let sample_size = matches.get_one::<usize>("sample").copied();
 
-        if omit_won && position.has_anyone_won() {
-            return false;
+    let mut invalid_count = 0;
+    let mut sorter = ExternalSorter::new(&get_sort_options(matches));
+    for entry in reader {
+        let entry = entry?;
+        if skip_invalid && entry.validate().is_err() {
+            invalid_count += 1;
+            continue;
         }
-
-        if omit_forced {
-            let enemy_threats = position.to_other_perspective().get_immediate_wins();
-            let is_forced_move = enemy_threats.0 != 0;
-            if is_forced_move {
-                return false;
-            }
+        if validate {
+            entry.validate()?;
         }
+        if filter.is_included(&entry) {
+            sorter.push(entry)?;
+        }
+    }
+    if invalid_count > 0 {
+        eprintln!("Skipped {} invalid positions", invalid_count);
+    }
 
-        true
-    });
+    // the same position may be in the book more than once, also as its mirror image
+    let mut entries = MergeDuplicates::new(sorter.finish()?, get_conflict_policy(matches));
+    let result = write_merged_entries(matches, &mut entries, book_format, sample_size);
+    for position in entries.conflicts() {
+        eprintln!("Conflicting scores for {}", position.as_hex_string());
+    }
+    result
+}
 
+/// The rest of format_book after the entries have been sorted and merged
+fn write_merged_entries(
+    matches: &ArgMatches,
+    entries: &mut impl Iterator<Item = Result<BookEntry, Error>>,
+    book_format: BookFormat,
+    sample_size: Option<usize>,
+) -> Result<(), Error> {
     if matches.get_flag("count-only") {
-        println!("{}", filtered_entries.count());
+        // counted after merging so that the count is the number of entries that would be written
+        let mut count = 0;
+        for entry in entries {
+            entry?;
+            count += 1;
+        }
+        println!("{}", sample_size.map_or(count, |size| count.min(size)));
         return Ok(());
     }
 
-    let writer: Box<dyn Write> = match get_path_arg(&matches, "out") {
+    let writer: Box<dyn Write> = match get_path_arg(matches, "out") {
         None => Box::new(io::stdout()),
         Some(path) => {
             let writer = LineWriter::new(File::create(path)?);
@@ -146,19 +234,370 @@ pub fn format_book(matches: &ArgMatches) -> Result<(), std::io::Error> {
         }
     };
 
-    let mut book_writer = BookWriter::create(writer, book_format);
-    for entry in filtered_entries {
-        let entry = entry;
-        book_writer.write_entry(entry)?;
+    let mut book_writer = BookWriter::create_sorted(writer, book_format);
+    if let Some(sample_size) = sample_size {
+        // the sample is chosen after sorting so that duplicates have been removed
+        let mut sampler = Sampler::new(sample_size, *matches.get_one::<u64>("seed").unwrap());
+        for entry in entries {
+            sampler.push(entry?);
+        }
+        for entry in sampler.finish() {
+            book_writer.write_entry(&entry)?;
+        }
+    } else {
+        for entry in entries {
+            book_writer.write_entry(&entry?)?;
+        }
+    }
+    book_writer.finish()?;
+    Ok(())
+}
+
+fn get_book_filter(matches: &ArgMatches) -> Result<BookFilter, Error> {
+    let root = match get_string_arg(matches, "reachable-from") {
+        Some(variation) => Some(
+            PositionInput::Variation(variation.to_string()).parse(matches.get_flag("validate"))?,
+        ),
+        None => None,
+    };
+    Ok(BookFilter {
+        plies: matches
+            .get_many::<u32>("ply")
+            .map_or(vec![], |plies| plies.copied().collect()),
+        scores: matches
+            .get_many::<String>("score")
+            .map_or(vec![], |scores| {
+                scores.map(|score| Score::from_string(score)).collect()
+            }),
+        symmetric_only: matches.get_flag("symmetric"),
+        root,
+        omit_won: matches.get_flag("omit-won"),
+        omit_forced: matches.get_flag("omit-forced"),
+    })
+}
+
+fn generate_book_subcommand(matches: &ArgMatches) -> Result<(), Error> {
+    let validate = matches.get_flag("validate");
+    let mut options = GenerateOptions::standard(*matches.get_one::<u32>("ply").unwrap());
+    options.out = get_path_arg(matches, "out");
+    options.use_book = get_path_arg(matches, "use-book");
+    options.validate = validate;
+    options.annotate = matches.get_flag("annotate");
+
+    if let Some(root) = get_string_arg(matches, "root") {
+        options.roots = vec![PositionInput::Variation(root.to_string()).parse(validate)?];
+    }
+    if let Some(path) = get_path_arg(matches, "positions") {
+        options.roots = read_positions_file(path, validate)?;
+        if matches.value_source("ply") != Some(ValueSource::CommandLine) {
+            options.ply = PlyTarget::Relative(0);
+        }
     }
+    if let Some(depth) = matches.get_one::<u32>("depth") {
+        options.ply = PlyTarget::Relative(*depth);
+    }
+    if matches.get_flag("external") {
+        options.external = Some(get_sort_options(matches));
+    }
+    if let Some(shard) = get_string_arg(matches, "shard") {
+        options.shard = Some(shard.parse()?);
+    }
+    if let Some(shard_paths) = matches.get_many::<String>("merge-shards") {
+        let shard_paths: Vec<&Path> = shard_paths.map(Path::new).collect();
+        return merge_shards(&options, &shard_paths);
+    }
+    generate_book(&options)
+}
+
+fn verify_book_subcommand(matches: &ArgMatches) -> Result<(), Error> {
+    let book = get_path_arg(matches, "book").unwrap();
+    let reference_book = get_path_arg(matches, "reference_book").unwrap();
+    let get_format = |name| match get_string_arg(matches, name).unwrap() {
+        "detect" => Ok(None),
+        format => format.parse().map(Some),
+    };
+    let formats = [get_format("book-format")?, get_format("reference-format")?];
+    let validate = matches.get_flag("validate");
+    verify_book(
+        book,
+        reference_book,
+        formats,
+        validate,
+        &get_sort_options(matches),
+    )
+}
+
+fn extract_strategy_subcommand(matches: &ArgMatches) -> Result<(), Error> {
+    let validate = matches.get_flag("validate");
+    let root = match get_string_arg(matches, "root") {
+        Some(root) => PositionInput::Variation(root.to_string()).parse(validate)?,
+        None => Position::empty(),
+    };
+    let book = match get_path_arg(matches, "use-book") {
+        Some(path) => Book::open_validated(path, validate)?,
+        None => {
+            let book = Book::standard();
+            if validate {
+                book.validate()?;
+            }
+            book
+        }
+    };
+    let max_ply = *matches.get_one::<u32>("ply").unwrap();
+    let out = get_path_arg(matches, "out").unwrap();
+    let format: BookFormat = get_string_arg(matches, "out-format").unwrap().parse()?;
+    let count = extract_strategy(&root, max_ply, book, out, format)?;
+    println!(
+        "Saved a strategy of {} positions to {}",
+        count,
+        out.display()
+    );
     Ok(())
 }
 
-fn play(matches: &ArgMatches) -> Result<(), String> {
+/// Reads one variation or hex code per line. Empty lines and lines starting with # are skipped.
+fn read_positions_file(path: &Path, validate: bool) -> Result<Vec<Position>, Error> {
+    let file = File::open(path)?;
+    let mut positions = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        positions.push(PositionInput::Variation(line.to_string()).parse(validate)?);
+    }
+    Ok(positions)
+}
+
+fn diff_book(matches: &ArgMatches) -> Result<(), Error> {
+    let validate = matches.get_flag("validate");
+    let book_path = get_path_arg(matches, "book").unwrap();
+    let reference_path = get_path_arg(matches, "reference_book").unwrap();
+    let book = Book::open_validated(book_path, validate)?;
+    let reference_book = Book::open_validated(reference_path, validate)?;
+
+    let diff = book.diff(&reference_book);
+    // writes instead of printing so that a closed pipe is an error rather than a panic
+    let mut out = io::stdout().lock();
+    if get_string_arg(matches, "output") == Some("json") {
+        write_diff_json(&mut out, &diff)?;
+    } else {
+        write_diff_text(&mut out, &diff, book_path, reference_path)?;
+    }
+
+    if diff.conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Conflict {
+            count: diff.conflicts.len(),
+        })
+    }
+}
+
+fn write_diff_text(
+    out: &mut impl Write,
+    diff: &BookDiff,
+    path1: &Path,
+    path2: &Path,
+) -> io::Result<()> {
+    writeln!(out, "{} shared positions", diff.shared_count)?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{} positions with conflicting scores ({} / {}):",
+        diff.conflicts.len(),
+        path1.display(),
+        path2.display()
+    )?;
+    for (entry1, entry2) in &diff.conflicts {
+        writeln!(
+            out,
+            "{} {:?} / {:?}",
+            entry1.get_position().as_hex_string(),
+            entry1.get_score(),
+            entry2.get_score()
+        )?;
+    }
+    for (path, entries) in [(path1, &diff.only_in_first), (path2, &diff.only_in_second)] {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{} positions only in {}:",
+            entries.len(),
+            path.display()
+        )?;
+        for entry in entries {
+            writeln!(
+                out,
+                "{} {:?}",
+                entry.get_position().as_hex_string(),
+                entry.get_score()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn write_diff_json(out: &mut impl Write, diff: &BookDiff) -> io::Result<()> {
+    let format_entries = |entries: &[BookEntry]| -> String {
+        let items: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"position\":\"{}\",\"score\":\"{:?}\"}}",
+                    entry.get_position().as_hex_string(),
+                    entry.get_score()
+                )
+            })
+            .collect();
+        format!("[{}]", items.join(","))
+    };
+    let conflicts: Vec<String> = diff
+        .conflicts
+        .iter()
+        .map(|(entry1, entry2)| {
+            format!(
+                "{{\"position\":\"{}\",\"score\":\"{:?}\",\"reference_score\":\"{:?}\"}}",
+                entry1.get_position().as_hex_string(),
+                entry1.get_score(),
+                entry2.get_score()
+            )
+        })
+        .collect();
+    writeln!(
+        out,
+        "{{\"shared_count\":{},\"conflicts\":[{}],\"only_in_book\":{},\"only_in_reference\":{}}}",
+        diff.shared_count,
+        conflicts.join(","),
+        format_entries(&diff.only_in_first),
+        format_entries(&diff.only_in_second)
+    )
+}
+
+fn book_stats(matches: &ArgMatches) -> Result<(), Error> {
+    let book_path = get_path_arg(matches, "book").unwrap();
+    let reader = match get_string_arg(matches, "book-format").unwrap() {
+        "detect" => BookReader::open(book_path),
+        name => BookReader::open_with_format(book_path, name.parse()?),
+    }?;
+    let mut stats = BookStats::new();
+    for entry in reader {
+        stats.add_entry(&entry?);
+    }
+
+    let mut out = io::stdout().lock();
+    if get_string_arg(matches, "output") == Some("json") {
+        write_stats_json(&mut out, &stats)?;
+    } else {
+        write_stats_text(&mut out, &stats)?;
+    }
+    Ok(())
+}
+
+fn write_stats_text(out: &mut impl Write, stats: &BookStats) -> io::Result<()> {
+    let format_scores = |count: &dyn Fn(Score) -> usize| -> String {
+        let items: Vec<String> = SCORES
+            .iter()
+            .map(|score| format!("{} {}", score.to_word(), count(*score)))
+            .collect();
+        items.join(", ")
+    };
+    let write_ply = |out: &mut dyn Write, title: String, ply_stats: &PlyStats| -> io::Result<()> {
+        writeln!(out, "{}: {} entries", title, ply_stats.entry_count)?;
+        writeln!(
+            out,
+            "  scores for the player to move: {}",
+            format_scores(&|score| ply_stats.score_count(score))
+        )?;
+        writeln!(
+            out,
+            "  scores for the first player:   {}",
+            format_scores(&|score| ply_stats.first_player_score_count(score))
+        )?;
+        writeln!(
+            out,
+            "  symmetric {}, already won {}, forced move {}, invalid {}",
+            ply_stats.symmetric_count,
+            ply_stats.won_count,
+            ply_stats.forced_count,
+            ply_stats.invalid_count
+        )
+    };
+    for (ply, ply_stats) in stats.plies() {
+        write_ply(out, format!("Ply {}", ply), ply_stats)?;
+    }
+    write_ply(out, "Total".to_string(), &stats.total())
+}
+
+fn write_stats_json(out: &mut impl Write, stats: &BookStats) -> io::Result<()> {
+    let format_stats = |ply_stats: &PlyStats| -> String {
+        let format_scores = |count: &dyn Fn(Score) -> usize| -> String {
+            let items: Vec<String> = SCORES
+                .iter()
+                .map(|score| format!("\"{}\":{}", score.to_word(), count(*score)))
+                .collect();
+            format!("{{{}}}", items.join(","))
+        };
+        format!(
+            "\"entries\":{},\"scores\":{},\"first_player_scores\":{},\"symmetric\":{},\"won\":{},\"forced\":{},\"invalid\":{},\"unknown\":{}",
+            ply_stats.entry_count,
+            format_scores(&|score| ply_stats.score_count(score)),
+            format_scores(&|score| ply_stats.first_player_score_count(score)),
+            ply_stats.symmetric_count,
+            ply_stats.won_count,
+            ply_stats.forced_count,
+            ply_stats.invalid_count,
+            ply_stats.score_count(Score::Unknown)
+        )
+    };
+    let plies: Vec<String> = stats
+        .plies()
+        .map(|(ply, ply_stats)| format!("{{\"ply\":{},{}}}", ply, format_stats(ply_stats)))
+        .collect();
+    writeln!(
+        out,
+        "{{{},\"plies\":[{}]}}",
+        format_stats(&stats.total()),
+        plies.join(",")
+    )
+}
+
+fn get_conflict_policy(matches: &ArgMatches) -> ConflictPolicy {
+    match get_string_arg(matches, "on-conflict").unwrap() {
+        "prefer-exact" => ConflictPolicy::PreferExact,
+        "keep-reference" => ConflictPolicy::KeepReference,
+        _ => ConflictPolicy::Fail,
+    }
+}
+
+fn merge_book(matches: &ArgMatches) -> Result<(), Error> {
+    let validate = matches.get_flag("validate");
+    let policy = get_conflict_policy(matches);
+
+    let mut merged_book = Book::empty();
+    for path in matches.get_many::<String>("books").unwrap() {
+        let book = Book::open_validated(Path::new(path), validate)?;
+        merged_book = merged_book.merge(&book, policy)?;
+    }
+
+    let book_format: BookFormat = get_string_arg(matches, "out-format").unwrap().parse()?;
+    let file = LineWriter::new(File::create(get_path_arg(matches, "out").unwrap())?);
+    let mut book_writer = BookWriter::create_sorted(file, book_format);
+    for entry in merged_book.iter() {
+        book_writer.write_entry(&entry)?;
+    }
+    book_writer.finish()?;
+    println!("Merged {} positions", merged_book.len());
+    Ok(())
+}
+
+fn play(matches: &ArgMatches) -> Result<(), Error> {
     let use_book = !matches.get_flag("no-book");
     if use_book {
         let path_buf = get_path_for_ply(DEFAULT_BOOK_PLY);
-        let book_exists = path_buf.as_path().exists();
+        let book_exists =
+            path_buf.as_path().exists() || get_versioned_path_for_ply(DEFAULT_BOOK_PLY).exists();
         if !book_exists {
             println!(
                 "The book file {} does not exist. You can generate it with --generate-book",
@@ -170,24 +609,22 @@ fn play(matches: &ArgMatches) -> Result<(), String> {
     let variation = {
         let mut str = String::new();
         println!("Input variation:");
-        io::stdin()
-            .read_line(&mut str)
-            .expect("Failed to read line");
+        io::stdin().read_line(&mut str)?;
         str
     };
 
     let position_input = PositionInput::Variation(variation);
-    solve(position_input, use_book)
+    solve(position_input, use_book, matches.get_flag("validate"))
 }
 
-fn print_subcommand(matches: &ArgMatches) -> Result<(), String> {
-    let variation = get_string_arg(&matches, "variation").unwrap_or("");
+fn print_subcommand(matches: &ArgMatches) -> Result<(), Error> {
+    let variation = get_string_arg(matches, "variation").unwrap_or("");
     let position = if matches.get_flag("hex") {
         PositionInput::Hex(String::from(variation))
     } else {
         PositionInput::Variation(String::from(variation))
     }
-    .parse()?;
+    .parse(matches.get_flag("validate"))?;
 
     print_board(position);
 
@@ -254,8 +691,8 @@ fn print_board(position: Position) {
     );
 }
 
-fn solve(pos_input: PositionInput, use_book: bool) -> Result<(), String> {
-    let position = pos_input.parse()?;
+fn solve(pos_input: PositionInput, use_book: bool, validate: bool) -> Result<(), Error> {
+    let position = pos_input.parse(validate)?;
     print_board(position);
     if use_book {
         println!("Solving (book enabled)...");
@@ -266,22 +703,83 @@ fn solve(pos_input: PositionInput, use_book: bool) -> Result<(), String> {
     let mut engine = Engine::new();
     if use_book {
         let book = Box::new(Book::standard());
+        if validate {
+            book.validate()?;
+        }
+        book.build_lookup_index();
         engine.set_book(book);
     }
+    if let Some(book) = engine.get_book() {
+        print_move_scores(book, &position);
+    }
     engine.set_position(position);
     let benchmark = Benchmark::run(&mut engine);
     println!();
     benchmark.print();
+    if let Some(x) = engine.get_book().and_then(|book| book.best_move(&position)) {
+        println!("Best move from book: {}", x + 1);
+    }
     Ok(())
 }
 
+/// Prints the book scores of the moves if the book knows any of them. This is useful one ply before
+/// a book ply because then the moves are known without searching.
+fn print_move_scores(book: &Book, position: &Position) {
+    let moves = book.probe_moves(position);
+    if moves.iter().all(|(_, score)| *score == Score::Unknown) {
+        return;
+    }
+    println!("Move scores from book:");
+    for (x, score) in moves {
+        println!("  {}: {:?}", x + 1, score);
+    }
+}
+
 fn get_string_arg<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
     matches.get_one::<String>(name)
         .map(|s| s.as_str())
 }
 
 fn get_path_arg<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a Path> {
-    get_string_arg(matches, name).map(|s| Path::new(s))
+    get_string_arg(matches, name).map(Path::new)
+}
+
+/// How to merge different scores of the same position
+fn on_conflict_arg() -> Arg {
+    Arg::new("on-conflict")
+        .long("on-conflict")
+        .help(
+            "prefer-exact replaces a bound with a compatible exact score, e.g. DrawOrWin with Draw",
+        )
+        .value_parser(["prefer-exact", "fail", "keep-reference"])
+        .default_value("fail")
+}
+
+/// Arguments of the subcommands that sort books that may not fit in memory
+fn sort_args() -> [Arg; 2] {
+    [
+        Arg::new("temp-dir")
+            .long("temp-dir")
+            .help("Directory for temporary files when sorting on disk. Defaults to the temporary directory of the system.")
+            .value_name("DIR")
+            .num_args(1),
+        Arg::new("chunk-size")
+            .long("chunk-size")
+            .help("How many entries are sorted in memory at a time. Each takes 8 bytes.")
+            .value_name("ENTRIES")
+            .value_parser(value_parser!(u64).range(1..)),
+    ]
+}
+
+fn get_sort_options(matches: &ArgMatches) -> SortOptions {
+    let mut options = SortOptions::default();
+    if let Some(path) = get_path_arg(matches, "temp-dir") {
+        options.temp_dir = path.to_path_buf();
+    }
+    if let Some(chunk_size) = matches.get_one::<u64>("chunk-size") {
+        options.chunk_size = *chunk_size as usize;
+    }
+    options
 }
 
 fn main() {
@@ -295,6 +793,13 @@ fn main() {
                 .help("Disables opening book")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("validate")
+                .long("validate")
+                .help("Rejects input positions and books with positions that cannot occur in a real game")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
         .subcommand(
             Command::new("format-book")
                 .about("Converts a book to another format")
@@ -304,17 +809,68 @@ fn main() {
                 .arg(
                     Arg::new("in-format")
                         .long("in-format")
-                        .value_parser(["detect", "hex", "binary", "vianiato"])
+                        .value_parser(DETECTABLE_BOOK_FORMATS)
                         .default_value("detect"),
                 )
                 .arg(
                     Arg::new("out-format")
                         .long("out-format")
-                        .value_parser(["hex", "binary", "vianiato"])
+                        .value_parser(BOOK_FORMATS)
                         .default_value("hex"),
                 )
                 .arg(Arg::new("omit-forced").long("omit-forced").action(ArgAction::SetTrue))
-                .arg(Arg::new("omit-won").long("omit-won").action(ArgAction::SetTrue)),
+                .arg(Arg::new("omit-won").long("omit-won").action(ArgAction::SetTrue))
+                .arg(
+                    Arg::new("ply")
+                        .long("ply")
+                        .help("Keeps only positions that have one of these plies")
+                        .value_parser(value_parser!(u32))
+                        .value_delimiter(',')
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("score")
+                        .long("score")
+                        .help("Keeps only positions that have one of these scores for the player to move")
+                        .value_parser(SCORE_NAMES)
+                        .value_delimiter(',')
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("symmetric")
+                        .long("symmetric")
+                        .help("Keeps only positions that are their own mirror image")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("reachable-from")
+                        .long("reachable-from")
+                        .help("Keeps only positions that can be reached from this variation or hex code, or from its mirror image")
+                        .value_name("VARIATION")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("skip-invalid")
+                        .long("skip-invalid")
+                        .help("Leaves out positions that cannot occur in a real game instead of failing like --validate")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("sample")
+                        .long("sample")
+                        .help("Keeps only this many randomly chosen positions of those that pass the other filters")
+                        .value_name("COUNT")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .help("Seed for choosing the sample. The same seed always chooses the same sample from the same positions.")
+                        .value_parser(value_parser!(u64))
+                        .default_value("0"),
+                )
+                .arg(on_conflict_arg())
+                .args(sort_args()),
         )
         .subcommand(
             Command::new("generate-book")
@@ -324,6 +880,7 @@ fn main() {
                     Arg::new("ply")
                         .long("ply")
                         .help("Solves and saves all positions that have the specified ply")
+                        .value_parser(value_parser!(u32))
                         .default_value("8"),
                 )
                 .arg(
@@ -332,6 +889,108 @@ fn main() {
                         .help("Uses another book when solving positions. Useful if generating a lower-ply book when a higher-ply book already exists.")
                         .value_name("FILE")
                         .num_args(1)
+                )
+                .arg(
+                    Arg::new("annotate")
+                        .long("annotate")
+                        .help("Also saves the best move of each position. Solves children until one has the best score so it is slower.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("root")
+                        .long("root")
+                        .help("Solves only positions that can be reached from this variation or hex code")
+                        .value_name("POSITION")
+                        .num_args(1)
+                        .requires("out"),
+                )
+                .arg(
+                    Arg::new("positions")
+                        .long("positions")
+                        .help("Solves the variations or hex codes in a file, one per line. Use --ply or --depth to solve the positions after them instead.")
+                        .value_name("FILE")
+                        .num_args(1)
+                        .conflicts_with("root")
+                        .requires("out"),
+                )
+                .arg(
+                    Arg::new("depth")
+                        .long("depth")
+                        .help("Solves positions that have this many more plies than the root positions")
+                        .value_parser(value_parser!(u32))
+                        .conflicts_with("ply"),
+                )
+                .arg(
+                    Arg::new("shard")
+                        .long("shard")
+                        .help("Solves only the i-th of n equal parts of the positions, e.g. 1/4. Saved next to the book unless --out is given.")
+                        .value_name("i/n")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("merge-shards")
+                        .long("merge-shards")
+                        .help("Combines shard files into the book instead of solving. Fails if positions are missing, unexpected or in more than one shard.")
+                        .value_name("FILE")
+                        .num_args(1..)
+                        .conflicts_with("shard"),
+                )
+                .arg(
+                    Arg::new("external")
+                        .long("external")
+                        .help("Sorts positions on disk instead of in memory so that deep books can be generated. The book is written to FILE.partial until it is complete.")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(sort_args()),
+        )
+        .subcommand(
+            Command::new("derive-book")
+                .about("Creates a book by minimaxing the scores of a book that has a higher ply, without searching")
+                .arg(Arg::new("from").required(true).long("from").value_name("INPUT_FILE").num_args(1))
+                .arg(Arg::new("out").required(true).long("out").value_name("OUTPUT_FILE").num_args(1))
+                .arg(
+                    Arg::new("ply")
+                        .long("ply")
+                        .required(true)
+                        .value_parser(value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("out-format")
+                        .long("out-format")
+                        .value_parser(BOOK_FORMATS)
+                        .default_value("hex"),
+                ),
+        )
+        .subcommand(
+            Command::new("extract-strategy")
+                .about("Saves a winning strategy for the player to move, with one winning move for each position and every reply of the opponent")
+                .arg(Arg::new("out").required(true).long("out").value_name("OUTPUT_FILE").num_args(1))
+                .arg(
+                    Arg::new("ply")
+                        .long("ply")
+                        .help("Positions with more plies are left out")
+                        .value_parser(value_parser!(u32))
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::new("root")
+                        .long("root")
+                        .help("The variation or hex code of the position where the strategy starts. The player to move must be winning.")
+                        .value_name("POSITION")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("use-book")
+                        .long("use-book")
+                        .help("The book that the engine uses instead of the standard book")
+                        .value_name("FILE")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("out-format")
+                        .long("out-format")
+                        .value_parser(BOOK_FORMATS)
+                        .default_value("hex"),
                 ),
         )
         .subcommand(
@@ -378,45 +1037,176 @@ fn main() {
             Command::new("verify-book")
                 .about("Compares and verifies a book against a reference book")
                 .arg(Arg::new("book").index(1).required(true))
-                .arg(Arg::new("reference_book").index(2).required(true)),
+                .arg(Arg::new("reference_book").index(2).required(true))
+                .arg(
+                    Arg::new("book-format")
+                        .long("book-format")
+                        .value_parser(DETECTABLE_BOOK_FORMATS)
+                        .default_value("detect"),
+                )
+                .arg(
+                    Arg::new("reference-format")
+                        .long("reference-format")
+                        .value_parser(DETECTABLE_BOOK_FORMATS)
+                        .default_value("detect"),
+                )
+                .args(sort_args()),
+        )
+        .subcommand(
+            Command::new("audit-book")
+                .about("Solves the positions of a book without using any book and reports wrong scores")
+                .arg(Arg::new("book").index(1).required(true))
+                .arg(
+                    Arg::new("sample")
+                        .long("sample")
+                        .help("Audits only this many randomly chosen positions")
+                        .value_name("COUNT")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .help("Seed for choosing the sample")
+                        .value_parser(value_parser!(u64))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .long("threads")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("progress")
+                        .long("progress")
+                        .help("Saves solved positions to a file and skips positions that the file already has. Use the same file to continue an interrupted audit.")
+                        .value_name("FILE")
+                        .num_args(1),
+                ),
+        )
+        .subcommand(
+            Command::new("check-book-consistency")
+                .about("Checks that the scores of books agree with the minimaxed scores of the next higher ply")
+                .arg(Arg::new("books").index(1).required(true).num_args(1..)),
+        )
+        .subcommand(
+            Command::new("diff-book")
+                .about("Lists positions that have conflicting scores or that only one of two books has")
+                .arg(Arg::new("book").index(1).required(true))
+                .arg(Arg::new("reference_book").index(2).required(true))
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            Command::new("book-stats")
+                .about("Counts the entries of a book by ply, score and kind of position")
+                .arg(Arg::new("book").index(1).required(true))
+                .arg(
+                    Arg::new("book-format")
+                        .long("book-format")
+                        .value_parser(DETECTABLE_BOOK_FORMATS)
+                        .default_value("detect"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            Command::new("merge-book")
+                .about("Merges books into one. The first book is the reference when scores conflict.")
+                .arg(Arg::new("books").index(1).required(true).num_args(1..))
+                .arg(Arg::new("out").required(true).long("out").value_name("OUTPUT_FILE").num_args(1))
+                .arg(
+                    Arg::new("out-format")
+                        .long("out-format")
+                        .value_parser(BOOK_FORMATS)
+                        .default_value("hex"),
+                )
+                .arg(on_conflict_arg()),
         )
         .get_matches();
 
     let result = match matches.subcommand() {
-        Some(("format-book", sub_matches)) => {
-            format_book(sub_matches).map_err(|err| err.to_string())
-        }
-        Some(("generate-book", sub_matches)) => {
-            let ply = get_string_arg(&sub_matches, "ply").unwrap().parse().unwrap();
-            let use_book = get_path_arg(&sub_matches, "use-book");
-            generate_book(ply, use_book).map_err(|err| err.to_string())
+        Some(("format-book", sub_matches)) => format_book(sub_matches),
+        Some(("generate-book", sub_matches)) => generate_book_subcommand(sub_matches),
+        Some(("derive-book", sub_matches)) => {
+            let source = get_path_arg(sub_matches, "from").unwrap();
+            let out = get_path_arg(sub_matches, "out").unwrap();
+            let ply = *sub_matches.get_one::<u32>("ply").unwrap();
+            get_string_arg(sub_matches, "out-format")
+                .unwrap()
+                .parse()
+                .and_then(|format| {
+                    derive_book(source, ply, out, format, sub_matches.get_flag("validate"))
+                })
         }
+        Some(("extract-strategy", sub_matches)) => extract_strategy_subcommand(sub_matches),
         Some(("print", sub_matches)) => print_subcommand(sub_matches),
         Some(("solve", sub_matches)) => {
-            let variation = get_string_arg(&sub_matches, "variation").unwrap_or("");
+            let variation = get_string_arg(sub_matches, "variation").unwrap_or("");
             let pos_input = if sub_matches.get_flag("hex") {
                 PositionInput::Hex(String::from(variation))
             } else {
                 PositionInput::Variation(String::from(variation))
             };
-            solve(pos_input, false)
+            solve(pos_input, false, sub_matches.get_flag("validate"))
         }
         Some(("test", sub_matches)) => {
             let files: Vec<String> = sub_matches.get_many::<String>("files")
                 .expect("Files expected")
                 .cloned()
                 .collect();
-            run_test_files(&files)
+            run_test_files(&files, sub_matches.get_flag("validate"))
         }
-        Some(("verify-book", sub_matches)) => {
-            let book = get_path_arg(&sub_matches, "book").unwrap();
-            let reference_book = get_path_arg(&sub_matches, "reference_book").unwrap();
-            verify_book(book, reference_book).map_err(|err| err.to_string())
+        Some(("verify-book", sub_matches)) => verify_book_subcommand(sub_matches),
+        Some(("audit-book", sub_matches)) => {
+            let book = get_path_arg(sub_matches, "book").unwrap();
+            let options = AuditOptions {
+                sample_size: sub_matches.get_one::<usize>("sample").copied(),
+                seed: *sub_matches.get_one::<u64>("seed").unwrap(),
+                thread_count: *sub_matches.get_one::<u32>("threads").unwrap() as usize,
+                progress_path: get_path_arg(sub_matches, "progress"),
+            };
+            audit_book(book, &options, sub_matches.get_flag("validate"))
+        }
+        Some(("check-book-consistency", sub_matches)) => {
+            let books: Vec<&Path> = sub_matches
+                .get_many::<String>("books")
+                .unwrap()
+                .map(Path::new)
+                .collect();
+            check_book_consistency(&books, sub_matches.get_flag("validate"))
         }
+        Some(("diff-book", sub_matches)) => diff_book(sub_matches),
+        Some(("merge-book", sub_matches)) => merge_book(sub_matches),
+        Some(("book-stats", sub_matches)) => book_stats(sub_matches),
         _ => play(&matches),
     };
 
-    if let Err(str) = result {
-        eprintln!("{}", str);
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(exit_code(&err));
+    }
+}
+
+/// Exit codes follow the BSD sysexits convention where one exists
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Io(_) => 74,
+        Error::UnknownFormat(_) => 64,
+        Error::Parse(_) | Error::Validation { .. } | Error::Format(_) => 65,
+        Error::InvalidInput { source, .. } | Error::InBook { source, .. } => exit_code(source),
+        Error::ShardMismatch { .. } => 65,
+        Error::Conflict { .. }
+        | Error::AuditMismatch { .. }
+        | Error::Inconsistent { .. }
+        | Error::ScoreMismatch { .. } => 1,
     }
 }
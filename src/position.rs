@@ -1,7 +1,9 @@
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::{fmt, mem};
 use std::fmt::Formatter;
 use crate::bitboard::{BIT_HEIGHT, Bitboard, BOARD_HEIGHT, BOARD_WIDTH, BoardInteger, BOTTOM_ROW, EVEN_ROWS, FIRST_COLUMN, FULL_BOARD, GUTTER_ROW, ODD_ROWS};
+use crate::error::{ParseError, ValidationError};
 use crate::move_bitmap::MoveBitmap;
 use crate::score::Score;
 
@@ -30,17 +32,28 @@ impl Position {
         Position { current, other }
     }
 
-    pub fn from_position_code(code: BoardInteger) -> Option<Position> {
-        let silhouette = Bitboard(code).get_silhouette();
-        // every column must have at least one bit set that indicates the height
-        if (silhouette.0 & BOTTOM_ROW) != BOTTOM_ROW {
-            return None;
+    pub fn from_position_code(code: BoardInteger) -> Result<Position, ParseError> {
+        if Self::is_valid_position_code(code) {
+            Ok(Self::decode_position_code(code))
+        } else {
+            Err(ParseError::InvalidPositionCode { code })
         }
+    }
+
+    /// Every column must have at least one bit set that indicates the height
+    pub(crate) fn is_valid_position_code(code: BoardInteger) -> bool {
+        let silhouette = Bitboard(code).get_silhouette();
+        (silhouette.0 & BOTTOM_ROW) == BOTTOM_ROW
+    }
 
+    /// The same as from_position_code but without checking the code. Callers must make sure that
+    /// the code is valid, for example with is_valid_position_code.
+    pub(crate) fn decode_position_code(code: BoardInteger) -> Position {
+        let silhouette = Bitboard(code).get_silhouette();
         let both = (silhouette.0 >> 1) & FULL_BOARD;
         let current = Bitboard(code & both);
         let other = Bitboard(!code & both);
-        Some(Position { current, other })
+        Position { current, other }
     }
 
     pub fn as_bitboard(&self) -> Bitboard {
@@ -52,13 +65,28 @@ impl Position {
         Bitboard(board).get_silhouette()
     }
 
-    pub fn from_variation(variation: &str) -> Option<Position> {
-        let mut position = Position::empty();
-        for ch in variation.trim().chars() {
-            let column = Position::char_to_column(ch)?;
-            position = position.position_after_drop(column)?;
+    /// Plays the moves of a variation such as "4453" starting from the empty board. Columns can be
+    /// given either as digits starting from 1 or as letters starting from A.
+    pub fn from_variation(variation: &str) -> Result<Position, ParseError> {
+        Position::empty().position_after_variation(variation)
+    }
+
+    /// Like from_variation but starts from this position
+    pub fn position_after_variation(&self, variation: &str) -> Result<Position, ParseError> {
+        let mut position = *self;
+        for (index, ch) in variation.trim().chars().enumerate() {
+            let column = Position::char_to_column(ch).ok_or(ParseError::InvalidColumn {
+                index,
+                character: ch,
+            })?;
+            if position.has_anyone_won() {
+                return Err(ParseError::MoveAfterWin { index, column });
+            }
+            position = position
+                .position_after_drop(column)
+                .ok_or(ParseError::ColumnFull { index, column })?;
         }
-        Some(position)
+        Ok(position)
     }
 
     fn char_to_column(ch: char) -> Option<u32> {
@@ -78,29 +106,55 @@ impl Position {
     }
 
     /// The reverse of to_string
-    pub fn from_string(str: &str) -> Option<Position> {
+    pub fn from_string(str: &str) -> Result<Position, ParseError> {
         let mut first_player = Bitboard::empty();
         let mut second_player = Bitboard::empty();
 
         for (y, line) in str.split_whitespace().rev().enumerate() {
             for (x, ch) in line.chars().enumerate() {
+                let (x, y) = (x as u32, y as u32);
+                if ch != 'X' && ch != 'O' && ch != '.' {
+                    return Err(ParseError::InvalidCharacter {
+                        x,
+                        y,
+                        character: ch,
+                    });
+                }
+                if x >= BOARD_WIDTH || y >= BOARD_HEIGHT {
+                    return Err(ParseError::CellOutOfBounds { x, y });
+                }
                 match ch {
-                    'X' => first_player = first_player.set_disc(x as u32, y as u32),
-                    'O' => second_player = second_player.set_disc(x as u32, y as u32),
-                    '.' => {}
-                    _ => return None,
+                    'X' => first_player = first_player.set_disc(x, y),
+                    'O' => second_player = second_player.set_disc(x, y),
+                    _ => {}
                 }
             }
         }
 
-        let ply = first_player.0.count_ones() + second_player.0.count_ones();
-        if ply % 2 == 0 {
-            Some(Position::new(first_player, second_player))
+        let position = Position::new(first_player, second_player);
+        let floating = position.get_floating_discs();
+        if floating.0 != 0 {
+            let bit = floating.0.trailing_zeros();
+            return Err(ParseError::FloatingDisc {
+                x: bit / BIT_HEIGHT,
+                y: bit % BIT_HEIGHT,
+            });
+        }
+
+        if position.get_ply() % 2 == 0 {
+            Ok(position)
         } else {
-            Some(Position::new(second_player, first_player))
+            Ok(position.to_other_perspective())
         }
     }
 
+    /// Returns the discs that have an empty cell directly below them. Positions created by playing
+    /// moves never have any.
+    pub fn get_floating_discs(&self) -> Bitboard {
+        let both = self.both();
+        Bitboard(both & !((both << 1) | BOTTOM_ROW))
+    }
+
     pub fn to_other_perspective(&self) -> Position {
         Position {
             current: self.other,
@@ -214,7 +268,6 @@ impl Position {
     /// );
     /// assert_eq!(position.flip(), flipped);
     /// ```
-
     pub fn flip(&self) -> Position {
         Position {
             current: self.current.flip(),
@@ -257,14 +310,22 @@ impl Position {
         format!("{:0>16X}", self.to_position_code())
     }
 
-    pub fn from_hex_string(str: &str) -> Option<Position> {
+    pub fn from_hex_string(str: &str) -> Result<Position, ParseError> {
         let str = str.trim();
-        if str.len() == 2 * mem::size_of::<BoardInteger>() {
-            let code = BoardInteger::from_str_radix(str, 16).ok()?;
-            Position::from_position_code(code)
-        } else {
-            None
+        let length = str.chars().count();
+        if length != 2 * mem::size_of::<BoardInteger>() {
+            return Err(ParseError::InvalidHexLength { length });
         }
+
+        let mut code: BoardInteger = 0;
+        for (index, ch) in str.chars().enumerate() {
+            let digit = ch.to_digit(16).ok_or(ParseError::InvalidHexDigit {
+                index,
+                character: ch,
+            })?;
+            code = (code << 4) | digit as BoardInteger;
+        }
+        Position::from_position_code(code)
     }
 
     pub fn to_normalized_position_code(&self) -> (BoardInteger, bool) {
@@ -298,7 +359,7 @@ impl Position {
     fn all_colums_even(&self) -> bool {
         let both = self.both();
         for x in 0..BOARD_WIDTH {
-            let column = (both >> x * BIT_HEIGHT) & FIRST_COLUMN;
+            let column = (both >> (x * BIT_HEIGHT)) & FIRST_COLUMN;
             if (column + 1).trailing_zeros() % 2 != 0 {
                 return false;
             }
@@ -309,16 +370,32 @@ impl Position {
     #[allow(dead_code)]
     fn is_column_even(&self, x: u32) -> bool {
         let both = self.both();
-        let column = (both >> x * BIT_HEIGHT) & FIRST_COLUMN;
+        let column = (both >> (x * BIT_HEIGHT)) & FIRST_COLUMN;
         (column + 1).trailing_zeros() % 2 == 0
     }
 
     pub fn guess_variation(&self) -> Option<String> {
-        fn recurse(target: &Position, variation: &mut String) -> bool {
-            let current_position = Position::from_variation(variation).unwrap();
+        self.guess_variation_from(&Position::empty())
+    }
+
+    /// Finds moves that lead from root to this position, or None if the position cannot be
+    /// reached from root. The search plays and takes back one move at a time and remembers the
+    /// positions it has already ruled out, so it visits each intermediate position at most once.
+    pub fn guess_variation_from(&self, root: &Position) -> Option<String> {
+        fn recurse(
+            current_position: Position,
+            target: &Position,
+            variation: &mut String,
+            dead_ends: &mut HashSet<Position>,
+        ) -> bool {
             if current_position == *target {
                 return true;
             }
+            // moves that continue a game that was already won are rejected so the search
+            // backtracks and tries another order
+            if current_position.has_anyone_won() || dead_ends.contains(&current_position) {
+                return false;
+            }
             let target_board = if current_position.is_white_moves() {
                 target.get_ordered_boards().0
             } else {
@@ -336,26 +413,77 @@ impl Position {
                 };
                 let y = current_position.get_height(x);
                 if target_board.has_disc(x, y) {
+                    let next_position = current_position.position_after_drop(x).unwrap();
                     let ch = std::char::from_digit(x + 1, 10).unwrap();
                     variation.push(ch);
-                    if recurse(target, variation) {
+                    if recurse(next_position, target, variation, dead_ends) {
                         return true;
                     } else {
                         variation.pop();
                     }
                 }
             }
+            dead_ends.insert(current_position);
             false
         }
 
+        // every disc of root must stay where it is, which also rules out most positions quickly
+        let (white, red) = self.get_ordered_boards();
+        let (root_white, root_red) = root.get_ordered_boards();
+        if root_white.0 & !white.0 != 0 || root_red.0 & !red.0 != 0 {
+            return None;
+        }
+
         let mut variation = String::new();
-        if recurse(self, &mut variation) {
+        if recurse(*root, self, &mut variation, &mut HashSet::new()) {
             Some(variation)
         } else {
             None
         }
     }
 
+    /// Checks that the position can occur in a real game and returns every violation found. Finding
+    /// a move order is the expensive part so it is only attempted when the cheaper checks pass.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        fn cells(board: BoardInteger) -> impl Iterator<Item = (u32, u32)> {
+            (0..BIT_HEIGHT * BOARD_WIDTH)
+                .filter(move |bit| board & (1 << bit) != 0)
+                .map(|bit| (bit / BIT_HEIGHT, bit % BIT_HEIGHT))
+        }
+
+        let mut errors = vec![];
+        for (x, y) in cells(self.current.0 & self.other.0) {
+            errors.push(ValidationError::OverlappingDisc { x, y });
+        }
+        for (x, y) in cells(self.get_floating_discs().0) {
+            errors.push(ValidationError::FloatingDisc { x, y });
+        }
+
+        let current = self.current.0.count_ones();
+        let other = self.other.0.count_ones();
+        if other != current && other != current + 1 {
+            errors.push(ValidationError::DiscCountMismatch { current, other });
+        }
+
+        if self.current.has_won() {
+            if self.other.has_won() {
+                errors.push(ValidationError::BothPlayersWon);
+            } else {
+                errors.push(ValidationError::MoveAfterWin);
+            }
+        }
+
+        if errors.is_empty() && self.guess_variation().is_none() {
+            errors.push(ValidationError::Unreachable);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     /// What happens if the other player always plays in the same column as the current player.
     /// The score is returned from the current player's perspective. If there are non-losing moves
     /// in an uneven column, the score cannot be determined and Unknown is returned.
@@ -497,6 +625,124 @@ mod tests {
         );
     }
 
+    #[test]
+    fn variation_errors() {
+        assert_eq!(
+            Position::from_variation("448"),
+            Err(ParseError::InvalidColumn {
+                index: 2,
+                character: '8'
+            })
+        );
+        assert_eq!(
+            Position::from_variation("4444444"),
+            Err(ParseError::ColumnFull {
+                index: 6,
+                column: 3
+            })
+        );
+        assert_eq!(
+            Position::from_variation("43434341"),
+            Err(ParseError::MoveAfterWin {
+                index: 7,
+                column: 0
+            })
+        );
+    }
+
+    #[test]
+    fn string_errors() {
+        assert_eq!(
+            Position::from_string("...X...\n.......\n"),
+            Err(ParseError::FloatingDisc { x: 3, y: 1 })
+        );
+        assert_eq!(
+            Position::from_string("...Y...\n"),
+            Err(ParseError::InvalidCharacter {
+                x: 3,
+                y: 0,
+                character: 'Y'
+            })
+        );
+        assert_eq!(
+            Position::from_string("...X....\n"),
+            Err(ParseError::CellOutOfBounds { x: 7, y: 0 })
+        );
+    }
+
+    #[test]
+    fn hex_errors() {
+        assert_eq!(
+            Position::from_hex_string("0000040812A0408"),
+            Err(ParseError::InvalidHexLength { length: 15 })
+        );
+        assert_eq!(
+            Position::from_hex_string("0000040812A0408G"),
+            Err(ParseError::InvalidHexDigit {
+                index: 15,
+                character: 'G'
+            })
+        );
+        assert_eq!(
+            Position::from_hex_string("0000040812A04081"),
+            Position::from_variation("4444")
+        );
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(
+            Position::from_variation("4455667").unwrap().validate(),
+            Ok(())
+        );
+
+        let extra_discs = Position::new(bitboard!("0001000" "0001000"), Bitboard::empty());
+        assert_eq!(
+            extra_discs.validate(),
+            Err(vec![ValidationError::DiscCountMismatch {
+                current: 2,
+                other: 0
+            }])
+        );
+
+        let floating = Position::new(Bitboard::empty(), bitboard!("0001000" "0000000"));
+        assert_eq!(
+            floating.validate(),
+            Err(vec![ValidationError::FloatingDisc { x: 3, y: 1 }])
+        );
+
+        // the second player cannot have moved first
+        let wrong_order = position!(
+            "...X..."
+            "...O..."
+        );
+        assert_eq!(
+            wrong_order.validate(),
+            Err(vec![ValidationError::Unreachable])
+        );
+
+        // X has two separate fours so the game must have continued after the first one
+        let two_wins = position!(
+            "X..X..."
+            "XO.X..."
+            "XOOXO.."
+            "XOOXO.."
+        );
+        assert_eq!(two_wins.validate(), Err(vec![ValidationError::Unreachable]));
+
+        // X has two separate fours on a nearly full board, where trying every move order takes too
+        // long without remembering the positions that were already ruled out
+        let crowded = position!(
+            "O.OOXOO"
+            "OXOXOOX"
+            "XXOOXOX"
+            "XXXXOXX"
+            "XOOXXXO"
+            "OOXXOOX"
+        );
+        assert_eq!(crowded.validate(), Err(vec![ValidationError::Unreachable]));
+    }
+
     #[test]
     fn height() {
         let position = Position::from_variation("436675553").unwrap();
@@ -528,6 +774,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn win_checking() {
         // horizontal
         {
@@ -665,4 +912,21 @@ mod tests {
         let position = Position::from_variation("4455").unwrap();
         assert!(position.all_colums_even());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn variation_from_root() {
+        let root = Position::from_variation("44").unwrap();
+        let position = Position::from_variation("44536").unwrap();
+        let variation = position.guess_variation_from(&root).unwrap();
+        assert_eq!(root.position_after_variation(&variation), Ok(position));
+        assert_eq!(
+            position.guess_variation_from(&position),
+            Some(String::new())
+        );
+
+        // the discs of the root are in different places
+        let other_root = Position::from_variation("45").unwrap();
+        assert_eq!(position.guess_variation_from(&other_root), None);
+        assert_eq!(root.guess_variation_from(&position), None);
+    }
+}
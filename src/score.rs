@@ -64,6 +64,8 @@ impl Score {
             "win" => Score::Win,
             "loss" => Score::Loss,
             "draw" => Score::Draw,
+            "draw-or-win" => Score::DrawOrWin,
+            "draw-or-loss" => Score::DrawOrLoss,
             _ => Score::Unknown,
         }
     }
@@ -101,6 +103,18 @@ impl Score {
             Score::Unknown => '?',
         }
     }
+
+    /// The inverse of from_string for scores that are longer than a character
+    pub fn to_word(&self) -> &'static str {
+        match self {
+            Score::Loss => "loss",
+            Score::DrawOrLoss => "draw-or-loss",
+            Score::Draw => "draw",
+            Score::DrawOrWin => "draw-or-win",
+            Score::Win => "win",
+            Score::Unknown => "unknown",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -118,6 +132,19 @@ mod tests {
         assert_eq!(Score::Unknown.flip(), Score::Unknown);
     }
 
+    #[test]
+    fn words() {
+        for score in [
+            Score::Loss,
+            Score::DrawOrLoss,
+            Score::Draw,
+            Score::DrawOrWin,
+            Score::Win,
+        ] {
+            assert_eq!(Score::from_string(score.to_word()), score);
+        }
+    }
+
     #[test]
     fn compatible_scores() {
         assert!(Score::Draw.is_compatible(Score::DrawOrWin));
@@ -0,0 +1,165 @@
+use crate::book::BookEntry;
+use crate::score::Score;
+use std::collections::BTreeMap;
+
+/// The scores in the order in which they are listed
+pub const SCORES: [Score; 6] = [
+    Score::Win,
+    Score::DrawOrWin,
+    Score::Draw,
+    Score::DrawOrLoss,
+    Score::Loss,
+    Score::Unknown,
+];
+
+/// Counts of the entries that have the same ply, or of all entries
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlyStats {
+    pub entry_count: usize,
+    /// Indexed by Score as usize
+    score_counts: [usize; 6],
+    /// Like score_counts but the scores are for the first player instead of the player to move
+    first_player_score_counts: [usize; 6],
+    /// Positions that are their own mirror image
+    pub symmetric_count: usize,
+    /// Positions where either player has already won
+    pub won_count: usize,
+    /// Positions where the opponent threatens to win immediately
+    pub forced_count: usize,
+    /// Positions that cannot occur in a real game
+    pub invalid_count: usize,
+}
+
+impl PlyStats {
+    /// The number of entries with the score for the player to move
+    pub fn score_count(&self, score: Score) -> usize {
+        self.score_counts[score as usize]
+    }
+
+    /// The number of entries with the score for the first player
+    pub fn first_player_score_count(&self, score: Score) -> usize {
+        self.first_player_score_counts[score as usize]
+    }
+
+    fn add(&mut self, other: &PlyStats) {
+        self.entry_count += other.entry_count;
+        for i in 0..self.score_counts.len() {
+            self.score_counts[i] += other.score_counts[i];
+            self.first_player_score_counts[i] += other.first_player_score_counts[i];
+        }
+        self.symmetric_count += other.symmetric_count;
+        self.won_count += other.won_count;
+        self.forced_count += other.forced_count;
+        self.invalid_count += other.invalid_count;
+    }
+}
+
+/// Statistics of a book that are collected one entry at a time, so the book does not need to fit
+/// in memory. Every entry is counted, including duplicates.
+#[derive(Clone, Debug, Default)]
+pub struct BookStats {
+    plies: BTreeMap<u32, PlyStats>,
+}
+
+impl BookStats {
+    pub fn new() -> BookStats {
+        Self::default()
+    }
+
+    pub fn add_entry(&mut self, entry: &BookEntry) {
+        let position = entry.get_position();
+        let ply = position.get_ply();
+        let stats = self.plies.entry(ply).or_default();
+
+        stats.entry_count += 1;
+        let score = entry.get_score();
+        stats.score_counts[score as usize] += 1;
+        let first_player_score = if ply % 2 == 0 { score } else { score.flip() };
+        stats.first_player_score_counts[first_player_score as usize] += 1;
+
+        if position.flip() == position {
+            stats.symmetric_count += 1;
+        }
+        if position.has_anyone_won() {
+            stats.won_count += 1;
+        }
+        if position.to_other_perspective().get_immediate_wins().0 != 0 {
+            stats.forced_count += 1;
+        }
+        if entry.validate().is_err() {
+            stats.invalid_count += 1;
+        }
+    }
+
+    /// The plies that have entries in ascending order together with their statistics
+    pub fn plies(&self) -> impl Iterator<Item = (u32, &PlyStats)> {
+        self.plies.iter().map(|(ply, stats)| (*ply, stats))
+    }
+
+    pub fn total(&self) -> PlyStats {
+        let mut total = PlyStats::default();
+        for stats in self.plies.values() {
+            total.add(stats);
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::tests::test_book;
+    use crate::position::Position;
+
+    #[test]
+    fn count_book() {
+        let mut stats = BookStats::new();
+        let book = test_book(4);
+        for entry in book.iter() {
+            stats.add_entry(&entry);
+        }
+        let plies: Vec<u32> = stats.plies().map(|(ply, _)| ply).collect();
+        assert_eq!(plies, vec![4]);
+
+        let total = stats.total();
+        assert_eq!(total.entry_count, book.len());
+        let score_total: usize = SCORES.iter().map(|score| total.score_count(*score)).sum();
+        assert_eq!(score_total, book.len());
+        // the first player is to move at ply 4
+        for score in SCORES {
+            assert_eq!(
+                total.score_count(score),
+                total.first_player_score_count(score)
+            );
+        }
+        assert!(total.symmetric_count > 0);
+        assert_eq!(total.won_count, 0);
+        assert_eq!(total.invalid_count, 0);
+        assert_eq!(total.score_count(Score::Unknown), 0);
+    }
+
+    #[test]
+    fn count_entries() {
+        let mut stats = BookStats::new();
+        // 4 is a loss for the second player, who is to move, and the position is symmetric
+        stats.add_entry(&BookEntry::new(
+            &Position::from_variation("4").unwrap(),
+            Score::Loss,
+        ));
+        // the second player has to block column 1
+        let position = Position::from_variation("12121").unwrap();
+        stats.add_entry(&BookEntry::new(&position, Score::Unknown));
+
+        let (ply, ply_stats) = stats.plies().next().unwrap();
+        assert_eq!(ply, 1);
+        assert_eq!(ply_stats.score_count(Score::Loss), 1);
+        assert_eq!(ply_stats.first_player_score_count(Score::Win), 1);
+        assert_eq!(ply_stats.symmetric_count, 1);
+
+        let total = stats.total();
+        assert_eq!(total.entry_count, 2);
+        assert_eq!(total.score_count(Score::Unknown), 1);
+        assert_eq!(total.forced_count, 1);
+        assert_eq!(total.symmetric_count, 1);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::bitboard::{BoardInteger, BOARD_WIDTH};
+use crate::book::{Book, BookEntry, BookFormat, BookWriter};
+use crate::engine::Engine;
+use crate::error::Error;
+use crate::position::Position;
+use crate::score::Score;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::LineWriter;
+use std::path::Path;
+
+/// Finds a winning strategy for the player to move in the root position: one winning move in each
+/// of the player's positions and every reply of the opponent. Only the player's positions are
+/// stored, annotated with the winning move, so the result is much smaller than a full book. The
+/// player is expected to take immediate wins without the strategy, so positions that have one are
+/// neither stored nor explored further.
+struct StrategyExtractor {
+    engine: Engine,
+    /// Positions with more plies are not explored
+    max_ply: u32,
+    /// The player's positions by normalized position code
+    entries: HashMap<BoardInteger, BookEntry>,
+}
+
+impl StrategyExtractor {
+    fn own_turn(&mut self, position: &Position) -> Result<(), Error> {
+        if position.get_ply() > self.max_ply || position.get_immediate_wins().0 != 0 {
+            return Ok(());
+        }
+        let (code, _) = position.to_normalized_position_code();
+        if self.entries.contains_key(&code) {
+            return Ok(());
+        }
+
+        let x = self.find_winning_move(position).ok_or_else(|| {
+            Error::Format(format!(
+                "Position {} has no winning move although it should have one. The book may have \
+                 wrong scores.",
+                position.as_hex_string()
+            ))
+        })?;
+        self.entries
+            .insert(code, BookEntry::annotated(position, Score::Win, Some(x)));
+        self.opponent_turn(&position.position_after_drop(x).unwrap())
+    }
+
+    fn opponent_turn(&mut self, position: &Position) -> Result<(), Error> {
+        let mut move_bitmap = position.get_legal_moves();
+        // the player's positions are looked up in normalized form so mirrored replies are the same
+        let (_, symmetric) = position.to_normalized_position_code();
+        if symmetric {
+            move_bitmap = move_bitmap.get_left_half();
+        }
+        for x in 0..BOARD_WIDTH {
+            if move_bitmap.has_move(x) {
+                self.own_turn(&position.position_after_drop(x).unwrap())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tries columns from the center outwards because central moves usually win more easily
+    fn find_winning_move(&mut self, position: &Position) -> Option<u32> {
+        center_first_columns().find(|x| match position.position_after_drop(*x) {
+            Some(child) => {
+                self.engine.set_position(child);
+                self.engine.solve() == Score::Loss
+            }
+            None => false,
+        })
+    }
+}
+
+fn center_first_columns() -> impl Iterator<Item = u32> {
+    let center = BOARD_WIDTH / 2;
+    (0..BOARD_WIDTH).map(move |i| {
+        if i % 2 == 0 {
+            center + (i + 1) / 2
+        } else {
+            center - (i + 1) / 2
+        }
+    })
+}
+
+/// Returns the strategy entries in ascending order of position codes
+fn find_strategy(root: &Position, max_ply: u32, book: Book) -> Result<Vec<BookEntry>, Error> {
+    let mut engine = Engine::new();
+    book.build_lookup_index();
+    engine.set_book(Box::new(book));
+    engine.set_position(*root);
+    let score = engine.solve();
+    if score != Score::Win {
+        return Err(Error::InvalidInput {
+            input: root.as_hex_string(),
+            source: Box::new(Error::ScoreMismatch {
+                code: root.to_position_code(),
+                expected: Score::Win,
+                actual: score,
+            }),
+        });
+    }
+
+    let mut extractor = StrategyExtractor {
+        engine,
+        max_ply,
+        entries: HashMap::new(),
+    };
+    extractor.own_turn(root)?;
+    let mut entries: Vec<BookEntry> = extractor.entries.into_values().collect();
+    entries.sort();
+    Ok(entries)
+}
+
+/// Extracts a winning strategy for the player to move in the root position up to the given ply
+/// and saves it as a book whose entries are annotated with the winning moves. Returns the number
+/// of saved positions.
+pub fn extract_strategy(
+    root: &Position,
+    max_ply: u32,
+    book: Book,
+    out_path: &Path,
+    format: BookFormat,
+) -> Result<usize, Error> {
+    let entries = find_strategy(root, max_ply, book)?;
+    let file = LineWriter::new(File::create(out_path)?);
+    let mut book_writer = BookWriter::create_sorted(file, format);
+    for entry in &entries {
+        book_writer.write_entry(entry)?;
+    }
+    book_writer.finish()?;
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_from_center() {
+        let columns: Vec<u32> = center_first_columns().collect();
+        assert_eq!(columns, vec![3, 2, 4, 1, 5, 0, 6]);
+    }
+
+    #[test]
+    fn strategy_covers_every_reply() {
+        let root = Position::from_variation("44444433").unwrap();
+        let entries = find_strategy(&root, 10, Book::empty()).unwrap();
+        let mut book_writer = BookWriter::create_sorted(vec![], BookFormat::Versioned);
+        for entry in &entries {
+            book_writer.write_entry(entry).unwrap();
+        }
+        let book = Book::from_bytes(&book_writer.finish().unwrap()).unwrap();
+
+        // every reply of the opponent must lead to a position that is in the strategy unless the
+        // reply allows an immediate win
+        let child = root
+            .position_after_drop(book.best_move(&root).unwrap())
+            .unwrap();
+        for x in 0..BOARD_WIDTH {
+            if let Some(position) = child.position_after_drop(x) {
+                if position.get_immediate_wins().0 == 0 {
+                    assert!(book.best_move(&position).is_some());
+                }
+            }
+        }
+        for entry in book.iter() {
+            assert_eq!(entry.get_score(), Score::Win);
+            assert!(entry.get_position().get_ply() == 8 || entry.get_position().get_ply() == 10);
+        }
+    }
+
+    #[test]
+    fn strategy_needs_winning_root() {
+        let root = Position::from_variation("444444332").unwrap();
+        let result = find_strategy(&root, 11, Book::empty());
+        match result {
+            Err(Error::InvalidInput { source, .. }) => assert!(matches!(
+                *source,
+                Error::ScoreMismatch {
+                    actual: Score::Draw | Score::Loss,
+                    ..
+                }
+            )),
+            _ => panic!("Expected an error for a root that is not a win"),
+        }
+    }
+}
@@ -80,7 +80,7 @@ class Position {
         try {
             this.wasmPosition = new wasm.Position(this.variation)
         } catch (e) {
-            throw Error('Invalid position')
+            throw Error('Invalid position: ' + e.message)
         }
     }
 
@@ -1,8 +1,9 @@
-use fourengine::bitboard::Bitboard;
+use fourengine::bitboard::{Bitboard, BOARD_WIDTH};
 use fourengine::book::Book;
 use fourengine::engine::Engine;
 use fourengine::position::{Disc, Position};
 use fourengine::score::Score;
+use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen(js_name = Position)]
@@ -13,15 +14,15 @@ pub struct JsPosition {
 #[wasm_bindgen(js_class = Position)]
 impl JsPosition {
     #[wasm_bindgen(constructor)]
-    pub fn new(variation: &str) -> JsPosition {
-        JsPosition {
-            position: Position::from_variation(variation).unwrap(),
-        }
+    pub fn new(variation: &str) -> Result<JsPosition, JsError> {
+        let position = Position::from_variation(variation)?;
+        Ok(JsPosition { position })
     }
 
     #[wasm_bindgen(js_name = fromHexString)]
-    pub fn from_hex_string(hex: &str) -> Option<JsPosition> {
-        Position::from_hex_string(hex).map(|position| JsPosition { position })
+    pub fn from_hex_string(hex: &str) -> Result<JsPosition, JsError> {
+        let position = Position::from_hex_string(hex)?;
+        Ok(JsPosition { position })
     }
 
     #[wasm_bindgen(js_name = toHexString)]
@@ -77,9 +78,10 @@ impl JsPosition {
     }
 }
 
+/// A book that can be given to several engines without copying it
 #[wasm_bindgen(js_name = Book)]
 pub struct JsBook {
-    book: Book,
+    book: Arc<Book>,
 }
 
 #[wasm_bindgen(js_class = Book)]
@@ -87,14 +89,36 @@ impl JsBook {
     #[wasm_bindgen(constructor)]
     pub fn new() -> JsBook {
         JsBook {
-            book: Book::empty()
+            book: Arc::new(Book::empty()),
+        }
+    }
+
+    /// Engines that already use this book keep the entries that it had when it was set
+    fn include(&mut self, book: &Book) {
+        match Arc::get_mut(&mut self.book) {
+            Some(own_book) => own_book.include_book(book),
+            None => {
+                let mut new_book = Book::empty();
+                new_book.include_book(&self.book);
+                new_book.include_book(book);
+                self.book = Arc::new(new_book);
+            }
         }
     }
 
     #[wasm_bindgen(js_name = includeLines)]
-    pub fn include_lines(&mut self, data: &str) {
-        let book = Book::from_lines(data).expect("Invalid book");
-        self.book.include_book(&book);
+    pub fn include_lines(&mut self, data: &str) -> Result<(), JsError> {
+        let book = Book::from_lines(data)?;
+        self.include(&book);
+        Ok(())
+    }
+
+    /// Includes a book in any binary format that Book::open detects, e.g. the compressed format
+    #[wasm_bindgen(js_name = includeBytes)]
+    pub fn include_bytes(&mut self, data: &[u8]) -> Result<(), JsError> {
+        let book = Book::from_bytes(data)?;
+        self.include(&book);
+        Ok(())
     }
 }
 
@@ -112,22 +136,47 @@ impl JsEngine {
         }
     }
 
+    /// The book is shared, so the same book can be set to several engines
     #[wasm_bindgen(js_name = setBook)]
-    pub fn set_book(&mut self, book: JsBook) {
-        self.engine.set_book(Box::new(book.book));
+    pub fn set_book(&mut self, book: &JsBook) {
+        self.engine.set_book(Arc::clone(&book.book));
+    }
+
+    /// Returns the best column (zero-based) from the book or undefined if the book does not have it
+    #[wasm_bindgen(js_name = bestMove)]
+    pub fn best_move(&self, variation: &str) -> Result<Option<u32>, JsError> {
+        let position = Position::from_variation(variation)?;
+        Ok(self
+            .engine
+            .get_book()
+            .and_then(|book| book.best_move(&position)))
+    }
+
+    /// Returns the book score of each column from the perspective of the player to move, e.g.
+    /// "Win" or "Unknown", or null if the column is full
+    #[wasm_bindgen(js_name = probeMoves)]
+    pub fn probe_moves(&self, variation: &str) -> Result<Vec<JsValue>, JsError> {
+        let position = Position::from_variation(variation)?;
+        let empty_book = Book::empty();
+        let book = self.engine.get_book().unwrap_or(&empty_book);
+        let mut scores = vec![JsValue::NULL; BOARD_WIDTH as usize];
+        for (x, score) in book.probe_moves(&position) {
+            scores[x as usize] = JsValue::from_str(&format!("{:?}", score));
+        }
+        Ok(scores)
     }
 
     #[wasm_bindgen]
-    pub fn solve(&mut self, variation: &str) -> Solution {
+    pub fn solve(&mut self, variation: &str) -> Result<Solution, JsError> {
         let engine = &mut self.engine;
-        let position = Position::from_variation(variation).unwrap();
+        let position = Position::from_variation(variation)?;
         engine.set_position(position);
         engine.work_count = 0;
         let score = engine.solve();
-        Solution {
+        Ok(Solution {
             score,
             work_count: engine.work_count,
-        }
+        })
     }
 }
 